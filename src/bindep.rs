@@ -7,23 +7,89 @@
 #[cfg(debug)]
 use pretty_assertions::assert;
 
+use error_chain::bail;
 use lazy_static::lazy_static;
 use log::{debug, info, warn};
 use memmap::Mmap;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
 use std::convert::AsRef;
+use std::fmt;
 use std::fs::File;
 use std::io::{Result as IoResult, Write};
 use std::ops::Deref;
 use std::path::{Path, PathBuf};
-use tar;
+use std::str::FromStr;
 
+use crate::csum::Checksum;
 use crate::errors::*;
+use crate::layout::{self, Collision, Layout};
+
+/// A shared library needed by a binary.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Library {
+    pub path: PathBuf,
+    /// The baseline library, when "path" is a variant optimized for the
+    /// CPU of the host. Packaged too, for nodes which cannot use the
+    /// variant.
+    pub fallback: Option<PathBuf>,
+    /// Whether the library was found in the library path, which is only
+    /// available on the host.
+    pub from_library_path: bool,
+}
+
+/// What to do about needed libraries which cannot be found.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MissingLibs {
+    Error,
+    Warn,
+    Ignore,
+}
+
+impl MissingLibs {
+    pub const NAMES: &'static [&'static str] = &["error", "warn", "ignore"];
+}
+
+impl FromStr for MissingLibs {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(MissingLibs::Error),
+            "warn" => Ok(MissingLibs::Warn),
+            "ignore" => Ok(MissingLibs::Ignore),
+            _ => Err(format!("unsupported missing libraries policy: {}", s)),
+        }
+    }
+}
+
+/// A needed library which could not be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MissingLibrary {
+    pub name: String,
+    pub needed_by: PathBuf,
+}
+
+impl fmt::Display for MissingLibrary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (needed by {})", self.name, self.needed_by.display())
+    }
+}
+
+/// Libraries needed by a binary, and the DT_RPATH directories which the
+/// dynamic loader also searches for the libraries they need in turn.
+#[derive(Debug)]
+pub struct Needed {
+    pub libraries: Vec<Library>,
+    /// Names of the libraries which could not be found.
+    pub missing: Vec<String>,
+    pub rpath: Vec<PathBuf>,
+}
 
 #[cfg(feature = "elf")]
 mod elf {
     use super::*;
-    use goblin::elf::{r#dyn as elfdyn, Elf};
+    use goblin::elf::header::{self as elfhdr, Header};
+    use goblin::elf::{dynamic as elfdyn, Elf};
     use goblin::error::Result as GobResult;
     use regex::{Captures, Regex};
 
@@ -32,113 +98,372 @@ mod elf {
             Regex::new(r"(?:\$\{(ORIGIN|LIB|PLATFORM)\}|\$(ORIGIN|LIB|PLATFORM))").unwrap();
     }
 
+    /// Linux extensions, used by binaries with GNU_IFUNC or unique symbols.
+    const ELFOSABI_GNU: u8 = 3;
+
+    /// The parts of the ELF header which the dynamic loader checks before
+    /// loading a library for a binary.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) struct Abi {
+        class: u8,
+        data: u8,
+        osabi: u8,
+        machine: u16,
+    }
+
+    impl Abi {
+        fn of(header: &Header) -> Self {
+            Abi {
+                class: header.e_ident[elfhdr::EI_CLASS],
+                data: header.e_ident[elfhdr::EI_DATA],
+                osabi: header.e_ident[elfhdr::EI_OSABI],
+                machine: header.e_machine,
+            }
+        }
+
+        /// Reads the ABI from the start of an ELF file, which may be
+        /// truncated after the "e_machine" field.
+        pub(super) fn parse(data: &[u8]) -> Option<Self> {
+            if data.len() < 20 || !data.starts_with(elfhdr::ELFMAG) {
+                return None;
+            }
+            let machine = [data[18], data[19]];
+            let machine = match data[elfhdr::EI_DATA] {
+                elfhdr::ELFDATA2LSB => u16::from_le_bytes(machine),
+                elfhdr::ELFDATA2MSB => u16::from_be_bytes(machine),
+                _ => return None,
+            };
+            Some(Abi {
+                class: data[elfhdr::EI_CLASS],
+                data: data[elfhdr::EI_DATA],
+                osabi: data[elfhdr::EI_OSABI],
+                machine,
+            })
+        }
+
+        fn read(path: &Path) -> Option<Self> {
+            use std::io::Read;
+            let mut header = [0; 20];
+            File::open(path).ok()?.read_exact(&mut header).ok()?;
+            Self::parse(&header)
+        }
+
+        /// Multiarch tuples used for the library directories of the ABI.
+        fn multiarch(self) -> &'static [&'static str] {
+            let is_64 = self.class == elfhdr::ELFCLASS64;
+            let is_le = self.data == elfhdr::ELFDATA2LSB;
+            match self.machine {
+                elfhdr::EM_X86_64 if is_64 => &["x86_64-linux-gnu"],
+                elfhdr::EM_X86_64 => &["x86_64-linux-gnux32"],
+                elfhdr::EM_386 => &["i386-linux-gnu"],
+                elfhdr::EM_AARCH64 if is_le => &["aarch64-linux-gnu"],
+                elfhdr::EM_AARCH64 => &["aarch64_be-linux-gnu"],
+                elfhdr::EM_ARM => &["arm-linux-gnueabihf", "arm-linux-gnueabi"],
+                elfhdr::EM_PPC64 if is_le => &["powerpc64le-linux-gnu"],
+                elfhdr::EM_PPC64 => &["powerpc64-linux-gnu"],
+                elfhdr::EM_PPC => &["powerpc-linux-gnu"],
+                elfhdr::EM_S390 if is_64 => &["s390x-linux-gnu"],
+                elfhdr::EM_RISCV if is_64 => &["riscv64-linux-gnu"],
+                _ => &[],
+            }
+        }
+
+        /// Library directory names for the ABI, relative to a prefix like
+        /// "/usr": the multiarch ones, the one for the ELF class used on
+        /// multilib systems, and plain "lib".
+        pub(super) fn lib_names(self) -> Vec<String> {
+            let mut names: Vec<String> = self
+                .multiarch()
+                .iter()
+                .map(|tuple| format!("lib/{}", tuple))
+                .collect();
+            names.push(String::from(match self.class {
+                elfhdr::ELFCLASS64 => "lib64",
+                _ => "lib32",
+            }));
+            names.push(String::from("lib"));
+            names
+        }
+
+        /// Default directories searched by the dynamic loader for libraries
+        /// with the ABI, under a root directory.
+        pub(super) fn system_dirs(self, root: &Path) -> Vec<PathBuf> {
+            let mut dirs = Vec::new();
+            for name in self.lib_names() {
+                dirs.push(root.join(&name));
+                dirs.push(root.join("usr").join(&name));
+            }
+            dirs
+        }
+
+        /// Value of "$PLATFORM" (AT_PLATFORM) for the ABI, when it does not
+        /// depend on the particular CPU.
+        fn platform(self) -> Option<&'static str> {
+            match self.machine {
+                elfhdr::EM_X86_64 => Some("x86_64"),
+                elfhdr::EM_386 => Some("i686"),
+                elfhdr::EM_AARCH64 => Some("aarch64"),
+                _ => None,
+            }
+        }
+
+        /// Whether a binary with this ABI can load a library with another.
+        pub(super) fn accepts(self, library: Abi) -> bool {
+            self.class == library.class
+                && self.data == library.data
+                && self.machine == library.machine
+                && (library.osabi == self.osabi
+                    || library.osabi == elfhdr::ELFOSABI_NONE
+                    || library.osabi == ELFOSABI_GNU)
+        }
+    }
+
     struct Libraries<'a> {
-        run_paths: Vec<String>,
+        abi: Abi,
+        /// Directories from DT_RPATH, followed by the inherited ones. Empty
+        /// when the binary has DT_RUNPATH.
+        rpath: Vec<PathBuf>,
+        runpath: Vec<PathBuf>,
+        /// DT_RPATH directories passed down to the needed libraries.
+        inherited_rpath: Vec<PathBuf>,
+        library_path: &'a [PathBuf],
+        /// Directories from the ldconfig configuration, followed by the
+        /// default ones for the ABI.
+        system_dirs: Vec<PathBuf>,
+        hwcaps: &'a [PathBuf],
         libraries: ::std::slice::Iter<'a, &'a str>,
     }
 
-    fn get_run_paths<'a>(elf: &'a Elf, base_path: &Path) -> Vec<String> {
+    /// Looks up a library in a list of directories, skipping libraries for
+    /// other architectures, e.g. 32-bit ones on multilib hosts, like the
+    /// dynamic loader does. Optimized variants in hwcaps subdirectories are
+    /// preferred over the library in the directory itself, which is also
+    /// returned as a fallback.
+    pub(super) fn find_library<'d, I>(
+        dirs: I,
+        lib: &str,
+        abi: Abi,
+        hwcaps: &[PathBuf],
+    ) -> Option<(PathBuf, Option<PathBuf>)>
+    where
+        I: IntoIterator<Item = &'d PathBuf>,
+    {
+        let compatible = |path: &PathBuf| {
+            path.exists()
+                && match Abi::read(path) {
+                    Some(library_abi) if abi.accepts(library_abi) => true,
+                    library_abi => {
+                        debug!("skipping {:?}, incompatible ABI {:?}", path, library_abi);
+                        false
+                    }
+                }
+        };
+        for dir in dirs {
+            let base = Some(dir.join(lib)).filter(compatible);
+            let variant = hwcaps
+                .iter()
+                .map(|subdir| dir.join(subdir).join(lib))
+                .find(compatible);
+            match (variant, base) {
+                (Some(variant), base) => return Some((variant, base)),
+                (None, Some(base)) => return Some((base, None)),
+                (None, None) => continue,
+            }
+        }
+        None
+    }
+
+    /// Expands the variables of a DT_RPATH or DT_RUNPATH entry. "$LIB" has a
+    /// value built into the dynamic loader, which depends on the system, so
+    /// every candidate for the ABI is returned. Nothing is returned when the
+    /// value of "$PLATFORM" is not known for the ABI.
+    pub(super) fn expand_run_path(path: &str, origin: &str, abi: Abi) -> Vec<String> {
+        let has_lib = RE
+            .captures_iter(path)
+            .any(|caps| caps.get(1).or_else(|| caps.get(2)).map(|m| m.as_str()) == Some("LIB"));
+        let libs = if has_lib {
+            abi.lib_names()
+        } else {
+            vec![String::new()]
+        };
+        let mut expanded = Vec::new();
+        for lib in libs {
+            let mut unknown = false;
+            let expansion = RE.replace_all(path, |caps: &Captures| {
+                match caps.get(1).or_else(|| caps.get(2)) {
+                    Some(m) => match m.as_str() {
+                        "ORIGIN" => String::from(origin),
+                        "LIB" => lib.clone(),
+                        "PLATFORM" => abi.platform().map(String::from).unwrap_or_else(|| {
+                            unknown = true;
+                            String::new()
+                        }),
+                        _ => unreachable!(),
+                    },
+                    None => unreachable!(),
+                }
+            });
+            if unknown {
+                warn!(
+                    "skipping run path \"{}\", unknown $PLATFORM for {:?}",
+                    path, abi
+                );
+                return vec![];
+            }
+            expanded.push(expansion.into_owned());
+        }
+        expanded
+    }
+
+    /// Expanded directories of the DT_RPATH or DT_RUNPATH entries of a
+    /// binary, or None if it has no entries with the tag.
+    fn get_run_paths(elf: &Elf, base_path: &Path, tag: u64) -> Option<Vec<PathBuf>> {
+        let has_tag = elf
+            .dynamic
+            .as_ref()
+            .is_some_and(|dynamic| dynamic.dyns.iter().any(|d| d.d_tag == tag));
+        if !has_tag {
+            return None;
+        }
+
         let base_path_str = base_path.to_str().unwrap();
+        let abi = Abi::of(&elf.header);
         let mut run_paths = vec![];
 
         if let Some(ref dynamic) = elf.dynamic {
-            for dynobj in &dynamic.dyns {
-                if dynobj.d_tag == elfdyn::DT_RPATH || dynobj.d_tag == elfdyn::DT_RUNPATH {
-                    match elf.dynstrtab.get(dynobj.d_val as usize) {
-                        Some(Ok(path)) => {
-                            // TODO: Expand $LIB and $PLATFORM.
+            for dynobj in dynamic.dyns.iter().filter(|d| d.d_tag == tag) {
+                match elf.dynstrtab.get(dynobj.d_val as usize) {
+                    Some(Ok(paths)) => {
+                        for path in paths.split(':').filter(|p| !p.is_empty()) {
                             debug!("expanding run path \"{}\"", path);
-                            let expanded = RE.replace_all(path, |caps: &Captures| {
-                                match caps.get(1).or_else(|| caps.get(2)) {
-                                    Some(m) => match m.as_str() {
-                                        "ORIGIN" => String::from(base_path_str),
-                                        "PLATFORM" => unimplemented!(),
-                                        "LIB" => unimplemented!(),
-                                        _ => unreachable!(),
-                                    },
-                                    None => unreachable!(),
-                                }
-                            });
-                            debug!("run path expanded to \"{}\"", expanded);
-                            match ::std::fs::canonicalize(expanded.as_ref()) {
-                                Ok(full_path) => {
-                                    debug!("run path canonicalized to {:?}", full_path);
-                                    run_paths.push(full_path.to_string_lossy().into());
+                            for expanded in expand_run_path(path, base_path_str, abi) {
+                                debug!("run path expanded to \"{}\"", expanded);
+                                match ::std::fs::canonicalize(&expanded) {
+                                    Ok(full_path) => {
+                                        debug!("run path canonicalized to {:?}", full_path);
+                                        run_paths.push(full_path);
+                                    }
+                                    Err(e) => warn!("cannot canonicalize path: {}", e),
                                 }
-                                Err(e) => warn!("cannot canonicalize path: {}", e),
                             }
                         }
-                        Some(Err(e)) => {
-                            // XXX: Should this error bubble up?
-                            warn!("error fetching strtab[{}]: {}", dynobj.d_val, e);
-                        }
-                        None => {
-                            warn!("failed to find [{:?}] in strtab", dynobj);
-                            println!("{:?}", elf.strtab);
-                        }
+                    }
+                    Some(Err(e)) => {
+                        // XXX: Should this error bubble up?
+                        warn!("error fetching strtab[{}]: {}", dynobj.d_val, e);
+                    }
+                    None => {
+                        warn!("failed to find [{:?}] in strtab", dynobj);
                     }
                 }
             }
         }
 
         debug!("run paths: {:?}", run_paths);
-        run_paths
+        Some(run_paths)
     }
 
     impl<'a> Libraries<'a> {
-        fn new(path: &'a Path, elf: &'a Elf) -> Self {
+        fn new(
+            path: &'a Path,
+            elf: &'a Elf,
+            inherited_rpath: &[PathBuf],
+            library_path: &'a [PathBuf],
+            hwcaps: &'a [PathBuf],
+        ) -> Self {
             assert!(path.is_absolute());
             assert!(path.is_file());
+            let base_path = path.parent().unwrap();
+            // The loader searches DT_RPATH of every binary in the chain which
+            // loaded a library, skipping binaries which have DT_RUNPATH. For
+            // the libraries needed by a binary with DT_RUNPATH, no DT_RPATH
+            // is searched at all, not even the inherited ones.
+            let runpath = get_run_paths(elf, base_path, elfdyn::DT_RUNPATH);
+            let mut inherited = match runpath {
+                Some(_) => vec![],
+                None => get_run_paths(elf, base_path, elfdyn::DT_RPATH).unwrap_or_default(),
+            };
+            inherited.extend(inherited_rpath.iter().cloned());
+            let rpath = if runpath.is_some() {
+                vec![]
+            } else {
+                inherited.clone()
+            };
+            let abi = Abi::of(&elf.header);
+            let mut system_dirs = crate::ldconfig::dirs().to_vec();
+            system_dirs.extend(abi.system_dirs(Path::new("/")));
             Libraries {
-                run_paths: get_run_paths(elf, path.parent().unwrap()),
+                abi,
+                rpath,
+                runpath: runpath.unwrap_or_default(),
+                inherited_rpath: inherited,
+                library_path,
+                system_dirs,
+                hwcaps,
                 libraries: elf.libraries.iter(),
             }
         }
 
-        fn resolve_path(&self, lib: &'a str) -> Option<PathBuf> {
-            // XXX: Do we need to handle the lib{32,64} madness? For now rely
-            // on the operating system providing the needed symbolic links.
-            // Should the environment variable $LD_LIBRARY_PATH be handled?
-
-            static LIBDIRS: &[&'static str] = &["/lib", "/usr/lib"];
-
-            let lib_dirs = LIBDIRS.iter().map(Deref::deref);
-            let run_paths = self.run_paths.iter().map(String::as_str);
+        /// Looks up a library in the same order as the dynamic loader:
+        /// DT_RPATH, the library path, DT_RUNPATH, and the system
+        /// directories.
+        fn resolve_path(&self, lib: &'a str) -> Option<Library> {
+            let find = |dirs: &[PathBuf]| find_library(dirs, lib, self.abi, self.hwcaps);
+            let found = |(path, fallback), from_library_path| Library {
+                path,
+                fallback,
+                from_library_path,
+            };
 
-            for lib_dir in run_paths.chain(lib_dirs) {
-                let path: PathBuf = [lib_dir, lib].into_iter().collect();
-                if path.exists() {
-                    return Some(path);
-                }
-            }
-
-            None
+            find(&self.rpath)
+                .map(|path| found(path, false))
+                .or_else(|| find(self.library_path).map(|path| found(path, true)))
+                .or_else(|| find(&self.runpath).map(|path| found(path, false)))
+                .or_else(|| find(&self.system_dirs).map(|path| found(path, false)))
         }
     }
 
     impl<'a> Iterator for Libraries<'a> {
-        type Item = PathBuf;
+        /// The library, or its name when it cannot be found.
+        type Item = ::std::result::Result<Library, &'a str>;
 
         fn next(&mut self) -> Option<Self::Item> {
-            loop {
-                match self.libraries.next() {
-                    None => return None,
-                    Some(ref lib_name) => match self.resolve_path(lib_name) {
-                        Some(path_buf) => return Some(path_buf),
-                        None => {
-                            warn!("cannot find path for \"{}\"", lib_name);
-                            continue;
-                        }
-                    },
-                }
-            }
+            let lib_name = self.libraries.next()?;
+            Some(self.resolve_path(lib_name).ok_or_else(|| {
+                debug!("cannot find path for \"{}\"", lib_name);
+                *lib_name
+            }))
         }
     }
 
-    pub fn libraries(path: &Path, data: &[u8]) -> GobResult<Vec<PathBuf>> {
-        Ok(Libraries::new(path, &Elf::parse(data)?)
-            .map(|p| p.to_path_buf())
-            .collect())
+    pub fn soname(data: &[u8]) -> Option<String> {
+        Elf::parse(data).ok()?.soname.map(String::from)
+    }
+
+    /// Finds the libraries needed by a binary, which was loaded by binaries
+    /// whose DT_RPATH directories are "inherited_rpath".
+    pub fn libraries(
+        path: &Path,
+        data: &[u8],
+        inherited_rpath: &[PathBuf],
+        library_path: &[PathBuf],
+        hwcaps: &[PathBuf],
+    ) -> GobResult<Needed> {
+        let elf = Elf::parse(data)?;
+        let mut libraries = Libraries::new(path, &elf, inherited_rpath, library_path, hwcaps);
+        let rpath = std::mem::take(&mut libraries.inherited_rpath);
+        let mut needed = Needed {
+            libraries: vec![],
+            missing: vec![],
+            rpath,
+        };
+        for library in libraries {
+            match library {
+                Ok(library) => needed.libraries.push(library),
+                Err(name) => needed.missing.push(name.to_string()),
+            }
+        }
+        Ok(needed)
     }
 }
 
@@ -149,7 +474,8 @@ mod elf {
 pub trait TarBuilderExt {
     fn add<P: AsRef<Path>>(&mut self, file_path: &Path, tar_path: P, data: &[u8]) -> IoResult<()>;
     fn symlink<P: AsRef<Path>>(&mut self, dst: P, src: P) -> IoResult<()>;
-    fn empty<P: AsRef<Path>>(&mut self, path: P) -> IoResult<()>;
+    fn hardlink<P: AsRef<Path>>(&mut self, file_path: &Path, dst: P, src: P) -> IoResult<()>;
+    fn data<P: AsRef<Path>>(&mut self, path: P, data: &[u8]) -> IoResult<()>;
 }
 
 impl<W: Write> TarBuilderExt for tar::Builder<W> {
@@ -157,6 +483,8 @@ impl<W: Write> TarBuilderExt for tar::Builder<W> {
         let mut header = tar::Header::new_gnu();
         header.set_metadata(&file_path.metadata()?);
         header.set_path(tar_path)?;
+        // The contents may have been modified, e.g. stripped.
+        header.set_size(data.len() as u64);
         header.set_cksum();
         self.append(&header, data)
     }
@@ -166,42 +494,338 @@ impl<W: Write> TarBuilderExt for tar::Builder<W> {
         header.set_entry_type(tar::EntryType::Symlink);
         header.set_link_name(dst)?;
         header.set_path(src)?;
+        header.set_mode(0o777);
+        header.set_size(0);
         header.set_cksum();
         self.append(&header, &[] as &[u8])
     }
 
-    fn empty<P: AsRef<Path>>(&mut self, path: P) -> IoResult<()> {
+    fn hardlink<P: AsRef<Path>>(&mut self, file_path: &Path, dst: P, src: P) -> IoResult<()> {
         let mut header = tar::Header::new_gnu();
-        header.set_entry_type(tar::EntryType::Regular);
-        header.set_path(path)?;
+        header.set_metadata(&file_path.metadata()?);
+        header.set_entry_type(tar::EntryType::Link);
+        header.set_link_name(dst)?;
+        header.set_path(src)?;
         header.set_size(0);
         header.set_cksum();
         self.append(&header, &[] as &[u8])
     }
+
+    fn data<P: AsRef<Path>>(&mut self, path: P, data: &[u8]) -> IoResult<()> {
+        let mut header = tar::Header::new_gnu();
+        header.set_entry_type(tar::EntryType::Regular);
+        header.set_path(path)?;
+        header.set_mode(0o644);
+        header.set_size(data.len() as u64);
+        header.set_cksum();
+        self.append(&header, data)
+    }
+}
+
+/// An entry to be written into the tarball.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Entry {
+    /// A file from the host at "source", stored at "path".
+    File { source: PathBuf, path: PathBuf },
+    /// A file from the host at "source", with the same contents as the
+    /// file stored at "target", stored as a hard link at "path".
+    Hardlink {
+        source: PathBuf,
+        target: PathBuf,
+        path: PathBuf,
+    },
+    /// A symbolic link at "path" which points to "target".
+    Symlink { target: PathBuf, path: PathBuf },
+    /// A regular file with generated contents.
+    Data { path: PathBuf, data: Vec<u8> },
+}
+
+impl Entry {
+    /// Path of the entry inside the tarball.
+    pub fn tar_path(&self) -> &Path {
+        match self {
+            Entry::File { path, .. } => path,
+            Entry::Hardlink { path, .. } => path,
+            Entry::Symlink { path, .. } => path,
+            Entry::Data { path, .. } => path,
+        }
+    }
+
+    /// Path of the host file with the contents of the entry, if any.
+    pub fn source(&self) -> Option<&Path> {
+        match self {
+            Entry::File { source, .. } => Some(source),
+            Entry::Hardlink { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+
+    fn set_tar_path(&mut self, new_path: PathBuf) {
+        match self {
+            Entry::File { path, .. } => *path = new_path,
+            Entry::Hardlink { path, .. } => *path = new_path,
+            Entry::Symlink { path, .. } => *path = new_path,
+            Entry::Data { path, .. } => *path = new_path,
+        }
+    }
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Entry::File { source, .. } => write!(f, "{}", source.display()),
+            Entry::Hardlink { source, target, .. } => {
+                write!(f, "{} (same as {})", source.display(), target.display())
+            }
+            Entry::Symlink { target, path } => {
+                write!(f, "{} -> {}", path.display(), target.display())
+            }
+            Entry::Data { path, data } if data.is_empty() => {
+                write!(f, "{} (empty)", path.display())
+            }
+            Entry::Data { path, .. } => write!(f, "{} (generated)", path.display()),
+        }
+    }
 }
 
-pub struct Solver<W: Write> {
+#[derive(Debug)]
+pub struct Solver {
+    layout: Layout,
+    strip: bool,
+    /// Extra directories searched for shared libraries, as with
+    /// "LD_LIBRARY_PATH".
+    library_path: Vec<PathBuf>,
+    /// Subdirectories of the library directories with optimized variants
+    /// of the libraries, in priority order.
+    hwcaps: Vec<PathBuf>,
     files: HashSet<PathBuf>,
-    tar: tar::Builder<W>,
+    entries: Vec<Entry>,
+    /// Index into "entries" for each path in the tarball.
+    paths: HashMap<PathBuf, usize>,
+    /// Directories implied by the paths of the entries.
+    dirs: HashSet<PathBuf>,
+    /// Indexes of the regular files, by size, to find identical ones.
+    sizes: HashMap<u64, Vec<usize>>,
+    digests: layout::Digests,
+    collisions: Vec<Collision>,
+    missing: Vec<MissingLibrary>,
+    /// Files passed to "scan_file", in order.
+    roots: Vec<PathBuf>,
+    /// Files needed by each scanned file, in the order they were found.
+    dependencies: HashMap<PathBuf, Vec<PathBuf>>,
 }
 
-impl<W: Write> Solver<W> {
-    pub fn new(writer: W) -> Result<Self> {
-        let mut tar = tar::Builder::new(writer);
-        tar.symlink("bin", "sbin")?;
-        tar.symlink(".", "usr")?;
-        Ok(Solver {
+impl Default for Solver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Solver {
+    pub fn new() -> Self {
+        Self::with_layout(Layout::Merged)
+    }
+
+    pub fn with_layout(layout: Layout) -> Self {
+        let mut solver = Solver {
+            layout,
+            strip: false,
+            library_path: Vec::new(),
+            hwcaps: Vec::new(),
             files: HashSet::new(),
-            tar,
-        })
+            entries: Vec::new(),
+            paths: HashMap::new(),
+            dirs: HashSet::new(),
+            sizes: HashMap::new(),
+            digests: layout::Digests::default(),
+            collisions: Vec::new(),
+            missing: Vec::new(),
+            roots: Vec::new(),
+            dependencies: HashMap::new(),
+        };
+        for (target, path) in layout.symlinks() {
+            solver.symlink(target, path);
+        }
+        solver
+    }
+
+    /// Removes debug information and symbol tables from ELF files
+    /// when writing them into the tarball.
+    pub fn set_strip(&mut self, strip: bool) {
+        self.strip = strip;
     }
 
-    pub fn into_inner(self) -> tar::Builder<W> {
-        self.tar
+    /// Directories searched for shared libraries after DT_RPATH and
+    /// before DT_RUNPATH, like "LD_LIBRARY_PATH" does for the dynamic
+    /// loader.
+    pub fn set_library_path<I: IntoIterator<Item = PathBuf>>(&mut self, library_path: I) {
+        self.library_path = library_path.into_iter().collect();
     }
 
+    /// Searches subdirectories of the library directories, like
+    /// "glibc-hwcaps/x86-64-v3", for optimized variants of the libraries.
+    pub fn set_hwcaps(&mut self, hwcaps: Vec<PathBuf>) {
+        self.hwcaps = hwcaps;
+    }
+
+    /// Contents of a host file as written into the tarball.
+    pub fn contents(&self, path: &Path) -> Result<Contents> {
+        let file_map = map_file(path)?;
+        if self.strip {
+            if let Some(data) = crate::strip::strip(&file_map) {
+                return Ok(Contents::Stripped(data));
+            }
+        }
+        Ok(Contents::Mapped(file_map))
+    }
+
+    /// Files which were scanned explicitly, instead of being found as
+    /// dependencies of another file.
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Files needed by a scanned file.
+    pub fn dependencies(&self, path: &Path) -> &[PathBuf] {
+        self.dependencies.get(path).map_or(&[], Vec::as_slice)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+
+    /// Whether there is already an entry at a path of the tarball.
+    pub fn contains<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.paths.contains_key(&self.layout.place(path))
+    }
+
+    /// Entries which could not be added because their location in the
+    /// tarball was already taken by a different entry.
+    pub fn collisions(&self) -> &[Collision] {
+        &self.collisions
+    }
+
+    /// Needed libraries which could not be found, in the order they were
+    /// looked up.
+    pub fn missing(&self) -> &[MissingLibrary] {
+        &self.missing
+    }
+
+    fn push(&mut self, mut entry: Entry) {
+        let path = self.layout.place(entry.tar_path());
+        entry.set_tar_path(path.clone());
+
+        let existing = match self.paths.get(&path) {
+            Some(&index) => Some(&self.entries[index]),
+            None => path
+                .ancestors()
+                .skip(1)
+                .find_map(|dir| self.paths.get(dir).map(|&index| &self.entries[index])),
+        };
+        if let Some(existing) = existing {
+            if existing.tar_path() == path && layout::is_duplicate(existing, &entry, &self.digests)
+            {
+                debug!("{} already added as {}, skipping", entry, existing);
+            } else {
+                self.collisions.push(Collision {
+                    path,
+                    existing: existing.to_string(),
+                    rejected: entry.to_string(),
+                });
+            }
+            return;
+        }
+        if self.dirs.contains(&path) {
+            self.collisions.push(Collision {
+                path,
+                existing: "a directory".to_string(),
+                rejected: entry.to_string(),
+            });
+            return;
+        }
+
+        for dir in path.ancestors().skip(1) {
+            if !self.dirs.insert(dir.to_path_buf()) {
+                break;
+            }
+        }
+        if let Entry::File { source, path } = entry {
+            entry = self.link_identical(source, path);
+        }
+        self.paths
+            .insert(entry.tar_path().to_path_buf(), self.entries.len());
+        self.entries.push(entry);
+    }
+
+    /// Turns a file into a hard link when a file with the same contents,
+    /// e.g. the same compiler installed under several names, was added.
+    fn link_identical(&mut self, source: PathBuf, path: PathBuf) -> Entry {
+        let size = match source.metadata() {
+            Ok(metadata) => metadata.len(),
+            Err(_) => return Entry::File { source, path },
+        };
+        let candidates = self.sizes.entry(size).or_default();
+        for &index in candidates.iter() {
+            let existing = &self.entries[index];
+            if self.digests.same_file(existing.source().unwrap(), &source) {
+                debug!("{:?} is identical to {}", source, existing);
+                return Entry::Hardlink {
+                    source,
+                    target: existing.tar_path().to_path_buf(),
+                    path,
+                };
+            }
+        }
+        candidates.push(self.entries.len());
+        Entry::File { source, path }
+    }
+
+    pub fn symlink<P: AsRef<Path>>(&mut self, dst: P, src: P) {
+        self.push(Entry::Symlink {
+            target: dst.as_ref().to_path_buf(),
+            path: src.as_ref().to_path_buf(),
+        });
+    }
+
+    pub fn empty<P: AsRef<Path>>(&mut self, path: P) {
+        self.data(path, Vec::new());
+    }
+
+    pub fn data<P: AsRef<Path>, D: Into<Vec<u8>>>(&mut self, path: P, data: D) {
+        self.push(Entry::Data {
+            path: path.as_ref().to_path_buf(),
+            data: data.into(),
+        });
+    }
+
+    fn push_file(&mut self, path: &Path) {
+        self.push(Entry::File {
+            source: path.to_path_buf(),
+            path: path.to_path_buf(),
+        });
+    }
+
+    /// Adds a file as-is, without looking for its dependencies.
+    pub fn add_file(&mut self, path: &Path) {
+        if self.files.insert(path.to_path_buf()) {
+            self.push_file(path);
+        }
+    }
+
+    /// Adds a file and, recursively, everything it needs to run.
     pub fn scan_file(&mut self, path: &Path) -> Result<()> {
-        let needed_libraries = match self.files.replace(path.to_path_buf()) {
+        if !self.files.contains(path) {
+            self.roots.push(path.to_path_buf());
+        }
+        self.scan(path, &[])
+    }
+
+    /// Scans a file loaded by binaries whose DT_RPATH directories are
+    /// "rpath". Each file is scanned once, so for libraries loaded from
+    /// several places the first one found determines where their own
+    /// dependencies are looked up.
+    fn scan(&mut self, path: &Path, rpath: &[PathBuf]) -> Result<()> {
+        let (needed_libraries, rpath) = match self.files.replace(path.to_path_buf()) {
             Some(_) => {
                 debug!("file {:?} seen, skipping", path);
                 return Ok(());
@@ -209,25 +833,329 @@ impl<W: Write> Solver<W> {
             None => {
                 info!("scanning {:?}", path);
                 // TODO: Improve error reporting.
-                let file_map = {
-                    let file =
-                        File::open(&path).chain_err(|| format!("cannot open file {:?}", path))?;
-                    unsafe {
-                        Mmap::map(&file)
-                            .chain_err(|| format!("cannot create memmap for {:?}", path))?
-                    }
-                };
+                let file_map = map_file(path)?;
                 debug!("memmap has {} bytes", file_map.len());
-                self.tar
-                    .add(path, path.strip_prefix("/").unwrap(), &file_map)
-                    .chain_err(|| format!("cannot add {:?} to tar file", path))?;
-                elf::libraries(path, &file_map)
-                    .chain_err(|| format!("cannot parse ELF binary: {:?}", path))?
+                self.push_file(path);
+                if file_map.starts_with(b"#!") {
+                    // Interpreters are new executables, which inherit nothing.
+                    let interpreters = script_interpreter(&file_map)
+                        .chain_err(|| format!("cannot find interpreter for {:?}", path))?;
+                    (interpreters, vec![])
+                } else {
+                    let needed =
+                        elf::libraries(path, &file_map, rpath, &self.library_path, &self.hwcaps)
+                            .chain_err(|| format!("cannot parse ELF binary: {:?}", path))?;
+                    self.missing
+                        .extend(needed.missing.into_iter().map(|name| MissingLibrary {
+                            name,
+                            needed_by: path.to_path_buf(),
+                        }));
+                    let libraries = needed
+                        .libraries
+                        .into_iter()
+                        .flat_map(|library| {
+                            if library.from_library_path {
+                                // The baseline library works on every node.
+                                self.link_library(
+                                    library.fallback.as_ref().unwrap_or(&library.path),
+                                );
+                            }
+                            std::iter::once(library.path).chain(library.fallback)
+                        })
+                        .collect();
+                    (libraries, needed.rpath)
+                }
             }
         };
-        for library in needed_libraries {
-            self.scan_file(&library)?;
+        for library in &needed_libraries {
+            self.scan(library, &rpath)?;
         }
+        self.dependencies
+            .insert(path.to_path_buf(), needed_libraries);
         Ok(())
     }
+
+    /// The library path is not set on the remote side, so libraries found
+    /// through it get a link in "/lib", where the dynamic loader looks.
+    fn link_library(&mut self, path: &Path) {
+        let name = path.file_name().unwrap();
+        let link = Path::new("/lib").join(name);
+        if self.layout.place(&link) != self.layout.place(path) {
+            self.symlink(path, &link);
+        }
+    }
+
+    /// Writes the entries into a tarball, returning the checksums of the
+    /// contents of the regular files written.
+    pub fn write<W: Write>(&self, tar: &mut tar::Builder<W>) -> Result<Vec<(PathBuf, Checksum)>> {
+        let mut checksums = Vec::new();
+        // Source and checksum of the files written, for their hard links.
+        let mut files: HashMap<&Path, (&Path, Checksum)> = HashMap::new();
+        for entry in &self.entries {
+            match entry {
+                Entry::File { source, path } => {
+                    let file_map = self.contents(source)?;
+                    tar.add(source, path, &file_map)
+                        .chain_err(|| format!("cannot add {:?} to tar file", source))?;
+                    let checksum = Checksum::of(&file_map[..]);
+                    files.insert(entry.tar_path(), (source, checksum.clone()));
+                    checksums.push((entry.tar_path().to_path_buf(), checksum));
+                }
+                Entry::Hardlink { target, path, .. } => {
+                    let (target_source, checksum) = match files.get(target.as_path()) {
+                        Some(file) => file,
+                        None => bail!("hard link {:?} precedes its target {:?}", path, target),
+                    };
+                    // Use the metadata of the file being linked to.
+                    tar.hardlink(target_source, target, path)?;
+                    checksums.push((path.clone(), checksum.clone()));
+                }
+                Entry::Symlink { target, path } => tar.symlink(target, path)?,
+                Entry::Data { path, data } => {
+                    tar.data(path, data)?;
+                    checksums.push((path.clone(), Checksum::of(data)));
+                }
+            }
+        }
+        Ok(checksums)
+    }
+}
+
+/// Obtains the programs needed to run a script from its "#!" line.
+fn script_interpreter(data: &[u8]) -> Result<Vec<PathBuf>> {
+    let line = data[2..].split(|&c| c == b'\n').next().unwrap_or_default();
+    let line = std::str::from_utf8(line)?;
+    let mut words = line.split_whitespace();
+    let interpreter = match words.next() {
+        Some(word) => PathBuf::from(word),
+        None => bail!("empty interpreter line"),
+    };
+    // Handle "#!/usr/bin/env program" by looking up the program as well.
+    if interpreter.file_name() == Some("env".as_ref()) {
+        if let Some(program) = words.next() {
+            return Ok(vec![interpreter, crate::util::find_program(program, None)?]);
+        }
+    }
+    Ok(vec![interpreter])
+}
+
+/// Obtains the SONAME of a shared library, if it has one.
+pub fn soname(data: &[u8]) -> Option<String> {
+    elf::soname(data)
+}
+
+/// Contents of a file, either mapped as-is or modified in memory.
+pub enum Contents {
+    Mapped(Mmap),
+    Stripped(Vec<u8>),
+}
+
+impl Deref for Contents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Contents::Mapped(file_map) => file_map,
+            Contents::Stripped(data) => data,
+        }
+    }
+}
+
+pub fn map_file(path: &Path) -> Result<Mmap> {
+    let file = File::open(path).chain_err(|| format!("cannot open file {:?}", path))?;
+    Ok(unsafe { Mmap::map(&file).chain_err(|| format!("cannot create memmap for {:?}", path))? })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn new_solver_has_default_symlinks() {
+        let solver = Solver::new();
+        let entries: Vec<String> = solver.entries().iter().map(|e| e.to_string()).collect();
+        assert_eq!(vec!["sbin -> bin", "usr -> ."], entries);
+    }
+
+    #[test]
+    fn elf_abi_compatibility() {
+        let ident = |class: u8, data: u8, osabi: u8, machine: u16| {
+            let mut header = vec![0x7F, b'E', b'L', b'F', class, data, 1, osabi];
+            header.resize(18, 0);
+            match data {
+                1 => header.extend_from_slice(&machine.to_le_bytes()),
+                _ => header.extend_from_slice(&machine.to_be_bytes()),
+            }
+            elf::Abi::parse(&header).unwrap()
+        };
+        let x86_64 = ident(2, 1, 0, 62);
+        assert!(x86_64.accepts(ident(2, 1, 0, 62)));
+        assert!(x86_64.accepts(ident(2, 1, 3, 62)));
+        assert!(!x86_64.accepts(ident(1, 1, 0, 3)));
+        assert!(!x86_64.accepts(ident(2, 1, 0, 183)));
+        assert!(!x86_64.accepts(ident(2, 2, 0, 62)));
+        assert!(!x86_64.accepts(ident(2, 1, 9, 62)));
+        assert!(elf::Abi::parse(b"/* GNU ld script */").is_none());
+    }
+
+    #[test]
+    fn system_library_dirs() {
+        let x86_64: &[u8] = b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x03\0\x3e\0";
+        let i386: &[u8] = b"\x7fELF\x01\x01\x01\0\0\0\0\0\0\0\0\0\x03\0\x03\0";
+        let dir = tempdir::TempDir::new("bindep").unwrap();
+        let root = dir.path();
+        let write = |path: &str, data: &[u8]| {
+            let path = root.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        // Like on multilib hosts, where /lib has the 32-bit libraries.
+        let foo64 = write("usr/lib64/libfoo.so.1", x86_64);
+        let foo32 = write("usr/lib/libfoo.so.1", i386);
+        let bar = write("lib/x86_64-linux-gnu/libbar.so.1", x86_64);
+
+        let (x86_64, i386) = (
+            elf::Abi::parse(x86_64).unwrap(),
+            elf::Abi::parse(i386).unwrap(),
+        );
+        let find = |lib, abi: elf::Abi| {
+            elf::find_library(&abi.system_dirs(root), lib, abi, &[]).map(|(path, _)| path)
+        };
+        assert_eq!(Some(foo64), find("libfoo.so.1", x86_64));
+        assert_eq!(Some(foo32), find("libfoo.so.1", i386));
+        assert_eq!(Some(bar), find("libbar.so.1", x86_64));
+        assert_eq!(None, find("libbar.so.1", i386));
+    }
+
+    #[test]
+    fn run_path_variables() {
+        let abi = |header: &[u8]| elf::Abi::parse(header).unwrap();
+        let x86_64 = abi(b"\x7fELF\x02\x01\x01\0\0\0\0\0\0\0\0\0\x03\0\x3e\0");
+        let expand = |path: &str, abi| elf::expand_run_path(path, "/opt/cc/bin", abi);
+        assert_eq!(vec!["/opt/cc/bin/../lib"], expand("$ORIGIN/../lib", x86_64));
+        assert_eq!(
+            vec!["/opt/cc/bin/x86_64"],
+            expand("${ORIGIN}/$PLATFORM", x86_64)
+        );
+        assert_eq!(
+            vec![
+                "/opt/cc/bin/../lib/x86_64-linux-gnu",
+                "/opt/cc/bin/../lib64",
+                "/opt/cc/bin/../lib",
+            ],
+            expand("$ORIGIN/../$LIB", x86_64)
+        );
+        let mips = abi(b"\x7fELF\x01\x02\x01\0\0\0\0\0\0\0\0\0\0\x03\0\x08");
+        assert_eq!(
+            vec!["/usr/lib32/x", "/usr/lib/x"],
+            expand("/usr/${LIB}/x", mips)
+        );
+        assert!(expand("/opt/$PLATFORM/lib", mips).is_empty());
+    }
+
+    #[test]
+    fn script_interpreters() {
+        assert_eq!(
+            vec![PathBuf::from("/bin/sh")],
+            script_interpreter(b"#!/bin/sh -e\nexec as \"$@\"\n").unwrap()
+        );
+        let programs = script_interpreter(b"#! /usr/bin/env true\n").unwrap();
+        assert_eq!(PathBuf::from("/usr/bin/env"), programs[0]);
+        assert_eq!(Some("true".as_ref()), programs[1].file_name());
+        assert!(script_interpreter(b"#!\n").is_err());
+    }
+
+    #[test]
+    fn synthetic_entries_are_listed() {
+        let mut solver = Solver::new();
+        solver.symlink("clang", "bin/clang++");
+        solver.empty("proc/cpuinfo");
+        let entries: Vec<String> = solver.entries().iter().map(|e| e.to_string()).collect();
+        assert_eq!("bin/clang++ -> clang", entries[2]);
+        assert_eq!("proc/cpuinfo (empty)", entries[3]);
+        assert!(solver.contains("bin/clang++"));
+        assert!(solver.contains("/usr/bin/clang++"));
+        assert!(!solver.contains("bin/clang"));
+    }
+
+    #[test]
+    fn hardlinks_keep_target_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempdir::TempDir::new("bindep").unwrap();
+        let mut solver = Solver::new();
+        for name in &["cc", "gcc"] {
+            let path = dir.path().join(name);
+            std::fs::write(&path, b"compiler").unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o700)).unwrap();
+            solver.add_file(&path);
+        }
+        let mut tar = tar::Builder::new(Vec::new());
+        solver.write(&mut tar).unwrap();
+        let data = tar.into_inner().unwrap();
+        let mut archive = tar::Archive::new(data.as_slice());
+        let link = archive
+            .entries()
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .find(|entry| entry.header().entry_type() == tar::EntryType::Link)
+            .unwrap();
+        assert_eq!(0o700, link.header().mode().unwrap() & 0o7777);
+
+        solver.entries.reverse();
+        assert!(solver.write(&mut tar::Builder::new(Vec::new())).is_err());
+    }
+
+    #[test]
+    fn layout_collisions() {
+        let dir = tempdir::TempDir::new("bindep").unwrap();
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        let foo = write("foo", b"foo");
+        let foo_copy = write("foo-copy", b"foo");
+        let bar = write("bar", b"bar");
+        let file = |source: &Path, path: &str| Entry::File {
+            source: source.to_path_buf(),
+            path: PathBuf::from(path),
+        };
+
+        // Identical contents at the same location are written once.
+        let mut solver = Solver::new();
+        solver.push(file(&foo, "lib/libfoo.so"));
+        solver.push(file(&foo_copy, "usr/lib/libfoo.so"));
+        assert_eq!(3, solver.entries().len());
+        assert!(solver.collisions().is_empty());
+
+        solver.push(file(&bar, "usr/lib/libfoo.so"));
+        solver.data("proc", "file");
+        solver.empty("proc/cpuinfo");
+        solver.empty("usr/proc/cpuinfo");
+        solver.empty("lib");
+        let collisions: Vec<&Path> = solver
+            .collisions()
+            .iter()
+            .map(|c| c.path.as_path())
+            .collect();
+        assert_eq!(
+            vec![
+                Path::new("lib/libfoo.so"),
+                Path::new("proc/cpuinfo"),
+                Path::new("proc/cpuinfo"),
+                Path::new("lib"),
+            ],
+            collisions
+        );
+        assert_eq!(4, solver.entries().len());
+
+        // Different files are kept apart in the split layout.
+        let mut solver = Solver::with_layout(Layout::Split);
+        solver.push(file(&foo, "lib/libfoo.so"));
+        solver.push(file(&bar, "usr/lib/libfoo.so"));
+        assert_eq!(2, solver.entries().len());
+        assert!(solver.collisions().is_empty());
+    }
 }
@@ -13,7 +13,6 @@ use std::fs::File;
 use std::io::prelude::*;
 use std::io::{BufReader, BufWriter};
 use std::path::PathBuf;
-use xdg;
 
 use crate::errors::*;
 
@@ -36,10 +35,7 @@ impl Cache {
 
     #[cfg(test)]
     pub fn has<S: AsRef<str>>(&self, key: S) -> bool {
-        match self.xdg.find_cache_file(key.as_ref()) {
-            Some(_) => true,
-            None => false,
-        }
+        self.xdg.find_cache_file(key.as_ref()).is_some()
     }
 
     pub fn get<S: AsRef<str>>(&self, key: S) -> Result<Option<String>> {
@@ -0,0 +1,750 @@
+//
+// compiler.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use error_chain::bail;
+use lazy_static::lazy_static;
+use log::info;
+use regex::Regex;
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::str::FromStr;
+
+use crate::errors::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompilerKind {
+    Gcc,
+    Clang,
+    Rustc,
+}
+
+impl CompilerKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            CompilerKind::Gcc => "gcc",
+            CompilerKind::Clang => "clang",
+            CompilerKind::Rustc => "rustc",
+        }
+    }
+}
+
+/// Languages supported by GCC, each one compiled by its own front-end
+/// program.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    C,
+    Cxx,
+    ObjC,
+    ObjCxx,
+    Fortran,
+    D,
+    Go,
+    Ada,
+}
+
+impl Language {
+    pub const ALL: &'static [Language] = &[
+        Language::C,
+        Language::Cxx,
+        Language::ObjC,
+        Language::ObjCxx,
+        Language::Fortran,
+        Language::D,
+        Language::Go,
+        Language::Ada,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Language::C => "c",
+            Language::Cxx => "c++",
+            Language::ObjC => "objc",
+            Language::ObjCxx => "obj-c++",
+            Language::Fortran => "fortran",
+            Language::D => "d",
+            Language::Go => "go",
+            Language::Ada => "ada",
+        }
+    }
+
+    /// Program which compiles the language, installed in the GCC libexec
+    /// directory.
+    pub fn front_end(self) -> &'static str {
+        match self {
+            Language::C => "cc1",
+            Language::Cxx => "cc1plus",
+            Language::ObjC => "cc1obj",
+            Language::ObjCxx => "cc1objplus",
+            Language::Fortran => "f951",
+            Language::D => "d21",
+            Language::Go => "go1",
+            Language::Ada => "gnat1",
+        }
+    }
+
+    /// Driver for the language, when "gcc" is not enough to use it.
+    pub fn driver(self) -> Option<&'static str> {
+        match self {
+            Language::Cxx => Some("g++"),
+            Language::Fortran => Some("gfortran"),
+            Language::D => Some("gdc"),
+            Language::Go => Some("gccgo"),
+            _ => None,
+        }
+    }
+}
+
+impl FromStr for Language {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        Language::ALL
+            .iter()
+            .find(|language| language.name() == s)
+            .cloned()
+            .ok_or_else(|| format!("unsupported language: {}", s))
+    }
+}
+
+/// Languages to package support for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Languages {
+    /// All the languages installed.
+    All,
+    /// The given languages, which must be installed.
+    List(Vec<Language>),
+}
+
+impl FromStr for Languages {
+    type Err = String;
+
+    /// Parses a comma-separated list of languages, or "all".
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Languages::All);
+        }
+        let languages = s
+            .split(',')
+            .map(|name| name.trim().parse())
+            .collect::<std::result::Result<_, _>>()?;
+        Ok(Languages::List(languages))
+    }
+}
+
+/// Compiler version, with missing components set to zero.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl FromStr for Version {
+    type Err = Error;
+
+    /// Parses the leading dot-separated numbers of a version string, any
+    /// suffix like "-1ubuntu1" or "git" is ignored.
+    fn from_str(s: &str) -> Result<Self> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"^(\d+)(?:\.(\d+))?(?:\.(\d+))?").unwrap();
+        }
+        let cap = match RE.captures(s.trim()) {
+            Some(cap) => cap,
+            None => bail!(ErrorKind::CompilerInfoError("invalid version number")),
+        };
+        let component = |n| cap.get(n).map_or(Ok(0), |m| m.as_str().parse());
+        Ok(Self::new(
+            component(1).chain_err(|| "invalid major version")?,
+            component(2).chain_err(|| "invalid minor version")?,
+            component(3).chain_err(|| "invalid patch version")?,
+        ))
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+/// Identification of a compiler, as reported by its driver.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompilerInfo {
+    pub kind: CompilerKind,
+    pub vendor: Option<String>,
+    pub version: Version,
+    /// Target triplet, e.g. "x86_64-linux-gnu".
+    pub target: Option<String>,
+    /// Default C language standard, e.g. "gnu17".
+    pub default_std: Option<String>,
+}
+
+/// Known version lines. The "vendor" and "version" groups are extracted.
+static PATTERNS: &[(CompilerKind, &str)] = &[
+    // e.g. "gcc version 12.2.0 (Debian 12.2.0-14)"
+    (CompilerKind::Gcc, r"^gcc version (?P<version>\d[\w.]*)"),
+    // e.g. "clang version 6.0.0", "Ubuntu clang version 15.0.7",
+    // "Apple clang version 14.0.0 (clang-1400.0.29.202)", "AMD clang version 16.0.0"
+    (
+        CompilerKind::Clang,
+        r"^(?:(?P<vendor>.+?)\s+)?clang version (?P<version>\d[\w.\-~+]*)",
+    ),
+    // e.g. "Intel(R) oneAPI DPC++/C++ Compiler 2023.1.0 (2023.1.0.20230320)"
+    (
+        CompilerKind::Clang,
+        r"^(?P<vendor>Intel\(R\) oneAPI) DPC\+\+/C\+\+ Compiler (?P<version>\d[\w.]*)",
+    ),
+    // e.g. "rustc 1.75.0 (82e1608df 2023-12-21)", "rustc 1.77.0-nightly"
+    (CompilerKind::Rustc, r"^rustc (?P<version>\d[\w.\-]*)"),
+];
+
+/// Identifies a compiler from the output of running it with "-v", or
+/// "-vV" for rustc.
+pub fn identify(output: &str) -> Option<CompilerInfo> {
+    lazy_static! {
+        static ref REGEXES: Vec<(CompilerKind, Regex)> = PATTERNS
+            .iter()
+            .map(|(kind, pattern)| (*kind, Regex::new(pattern).unwrap()))
+            .collect();
+    }
+
+    let target = output
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("Target: ")
+                .or_else(|| line.strip_prefix("host: "))
+        })
+        .map(|target| target.trim().to_string());
+
+    for line in output.lines() {
+        for (kind, re) in REGEXES.iter() {
+            if let Some(cap) = re.captures(line.trim()) {
+                let version = match cap["version"].parse() {
+                    Ok(version) => version,
+                    Err(_) => continue,
+                };
+                return Some(CompilerInfo {
+                    kind: *kind,
+                    vendor: cap.name("vendor").map(|m| m.as_str().to_string()),
+                    version,
+                    target,
+                    default_std: None,
+                });
+            }
+        }
+    }
+    None
+}
+
+/// Identifies a compiler from its predefined macros, as printed when
+/// running it with "-dM -E". Unlike the "-v" output, these do not depend
+/// on the locale and are not altered by wrapper scripts.
+pub fn identify_macros(output: &str) -> Option<CompilerInfo> {
+    let macros: HashMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.strip_prefix("#define "))
+        .map(|line| match line.find(' ') {
+            Some(pos) => (&line[..pos], line[pos + 1..].trim()),
+            None => (line, ""),
+        })
+        .collect();
+
+    let number = |name: &str| macros.get(name).and_then(|v| v.parse().ok());
+    let (kind, prefix) = if macros.contains_key("__clang__") {
+        (
+            CompilerKind::Clang,
+            ["__clang_major__", "__clang_minor__", "__clang_patchlevel__"],
+        )
+    } else if macros.contains_key("__GNUC__") {
+        (
+            CompilerKind::Gcc,
+            ["__GNUC__", "__GNUC_MINOR__", "__GNUC_PATCHLEVEL__"],
+        )
+    } else {
+        return None;
+    };
+    let version = Version::new(
+        number(prefix[0])?,
+        number(prefix[1]).unwrap_or(0),
+        number(prefix[2]).unwrap_or(0),
+    );
+
+    let vendor = if macros.contains_key("__apple_build_version__") {
+        Some("Apple".to_string())
+    } else if macros.contains_key("__INTEL_LLVM_COMPILER") {
+        Some("Intel(R) oneAPI".to_string())
+    } else if kind == CompilerKind::Clang {
+        // e.g. "Ubuntu Clang 15.0.7", upstream builds use "Clang 15.0.7"
+        macros
+            .get("__VERSION__")
+            .map(|v| v.trim_matches('"'))
+            .and_then(|v| v.find("Clang ").map(|pos| v[..pos].trim()))
+            .filter(|v| !v.is_empty())
+            .map(str::to_string)
+    } else {
+        None
+    };
+
+    let default_std = macros.get("__STDC_VERSION__").and_then(|v| {
+        let name = match *v {
+            "199409L" => "c94",
+            "199901L" => "c99",
+            "201112L" => "c11",
+            "201710L" => "c17",
+            "202000L" => "c2x",
+            "202311L" => "c23",
+            _ => return None,
+        };
+        Some(if macros.contains_key("__STRICT_ANSI__") {
+            name.to_string()
+        } else {
+            name.replacen('c', "gnu", 1)
+        })
+    });
+
+    Some(CompilerInfo {
+        kind,
+        vendor,
+        version,
+        target: None,
+        default_std,
+    })
+}
+
+fn run_compiler(path: &OsStr, args: &[&str]) -> Result<Output> {
+    Command::new(path)
+        .args(args)
+        .env("LC_ALL", "C")
+        .output()
+        .chain_err(|| format!("cannot run compiler {:?}", path))
+}
+
+/// Identifies the compiler from the version information it prints, which
+/// may go to either stdout or stderr.
+fn identify_output(path: &OsStr, args: &[&str]) -> Result<Option<CompilerInfo>> {
+    let output = run_compiler(path, args)?;
+    let out = String::from_utf8_lossy(&output.stdout);
+    let err = String::from_utf8_lossy(&output.stderr);
+    Ok(identify(&out).or_else(|| identify(&err)))
+}
+
+/// Whether a compiler is named like rustc, which does not understand the
+/// options of C compilers, and needs an input file with "-v" alone.
+fn is_rustc(path: &OsStr) -> bool {
+    Path::new(path)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.starts_with("rustc"))
+}
+
+pub fn compiler_info(path: &OsStr) -> Result<CompilerInfo> {
+    if is_rustc(path) {
+        return match identify_output(path, &["-vV"])? {
+            Some(info) => Ok(info),
+            None => bail!(ErrorKind::CompilerInfoError("no version information")),
+        };
+    }
+
+    let output = run_compiler(path, &["-dM", "-E", "-x", "c", "/dev/null"])?;
+    let info = if output.status.success() {
+        identify_macros(&String::from_utf8_lossy(&output.stdout))
+    } else {
+        None
+    };
+
+    let mut info = match info {
+        Some(info) => info,
+        None => {
+            info!("cannot identify {:?} from its predefined macros", path);
+            // Try "-vV" too, in case rustc was installed under another name.
+            let info = match identify_output(path, &["-v"])? {
+                Some(info) => Some(info),
+                None => identify_output(path, &["-vV"])?,
+            };
+            match info {
+                Some(info) => info,
+                None => bail!(ErrorKind::CompilerInfoError("no version information")),
+            }
+        }
+    };
+
+    if info.target.is_none() {
+        let output = run_compiler(path, &["-dumpmachine"])?;
+        let target = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        if output.status.success() && !target.is_empty() {
+            info.target = Some(target);
+        }
+    }
+    Ok(info)
+}
+
+/// Specs files which GCC reads default options from: a "specs" file in its
+/// library directory, which replaces the built-in specs and is how some
+/// distributions enable flags by default, and those listed in $GCC_SPECS,
+/// which Gentoo uses e.g. for hardened profiles.
+pub fn gcc_config_files(path: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let output = match run_compiler(path.as_os_str(), &["--print-file-name", "specs"]) {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(_) => String::new(),
+    };
+    let specs = Path::new(output.trim());
+    if specs.is_absolute() && specs.is_file() {
+        info!("GCC specs: {:?}", specs);
+        files.push(specs.to_path_buf());
+    }
+
+    if let Some(value) = std::env::var_os("GCC_SPECS") {
+        for file in std::env::split_paths(&value) {
+            if file.is_absolute() && file.is_file() {
+                info!("GCC specs from $GCC_SPECS: {:?}", file);
+                files.push(file);
+            }
+        }
+    }
+    files
+}
+
+/// System directory for Clang configuration files used by distributions,
+/// in addition to the directory of the compiler binary.
+const CLANG_CONFIG_DIRS: &[&str] = &["/etc/clang"];
+
+/// Configuration files which Clang 16 and later read default options from:
+/// those reported as loaded, those for the other driver modes (which are
+/// links to the same binary in the tarball), and the files they include.
+pub fn clang_config_files(path: &Path) -> Vec<PathBuf> {
+    let output = match run_compiler(path.as_os_str(), &["-v"]) {
+        Ok(output) => String::from_utf8_lossy(&output.stderr).into_owned(),
+        Err(_) => String::new(),
+    };
+    let mut files: Vec<PathBuf> = output
+        .lines()
+        .filter_map(|line| line.strip_prefix("Configuration file: "))
+        .map(|file| PathBuf::from(file.trim()))
+        .collect();
+    let target = output
+        .lines()
+        .find_map(|line| line.strip_prefix("Target: "))
+        .map(str::trim);
+
+    let dirs = path
+        .parent()
+        .into_iter()
+        .chain(CLANG_CONFIG_DIRS.iter().map(Path::new));
+    for dir in dirs {
+        for name in clang_config_names(target) {
+            let file = dir.join(name);
+            if file.is_file() && !files.contains(&file) {
+                files.push(file);
+            }
+        }
+    }
+
+    let mut n = 0;
+    while n < files.len() {
+        for file in clang_config_includes(&files[n]) {
+            if !files.contains(&file) {
+                files.push(file);
+            }
+        }
+        n += 1;
+    }
+    files
+}
+
+fn clang_config_names(target: Option<&str>) -> Vec<String> {
+    let mut names = Vec::new();
+    for driver in &["clang", "clang++", "clang-cpp"] {
+        if let Some(target) = target {
+            names.push(format!("{}-{}.cfg", target, driver));
+        }
+        names.push(format!("{}.cfg", driver));
+    }
+    if let Some(target) = target {
+        names.push(format!("{}.cfg", target));
+    }
+    names
+}
+
+/// Files included from a configuration file with "@file", which are
+/// relative to the directory of the including file.
+fn clang_config_includes(path: &Path) -> Vec<PathBuf> {
+    let contents = std::fs::read_to_string(path).unwrap_or_default();
+    let dir = path.parent().unwrap_or_else(|| Path::new("/"));
+    contents
+        .lines()
+        .filter(|line| !line.trim_start().starts_with('#'))
+        .flat_map(str::split_whitespace)
+        .filter_map(|word| word.strip_prefix('@'))
+        .map(|file| dir.join(file))
+        .filter(|file| file.is_file())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn clang_config_names_and_includes() {
+        assert_eq!(
+            vec!["clang.cfg", "clang++.cfg", "clang-cpp.cfg"],
+            clang_config_names(None)
+        );
+        let names = clang_config_names(Some("x86_64-pc-linux-gnu"));
+        assert_eq!("x86_64-pc-linux-gnu-clang.cfg", names[0]);
+        assert_eq!("x86_64-pc-linux-gnu.cfg", names[names.len() - 1]);
+
+        let dir = TempDir::new("clang-config").unwrap();
+        let config = dir.path().join("clang.cfg");
+        std::fs::write(
+            &config,
+            "# @ignored.cfg\n-fstack-protector-strong @common.cfg\n@missing.cfg\n",
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("common.cfg"), "-D_FORTIFY_SOURCE=2\n").unwrap();
+        std::fs::write(dir.path().join("ignored.cfg"), "").unwrap();
+        assert_eq!(
+            vec![dir.path().join("common.cfg")],
+            clang_config_includes(&config)
+        );
+    }
+
+    #[test]
+    fn language_lists() {
+        assert_eq!(
+            Languages::List(vec![Language::C, Language::Fortran]),
+            "c, fortran".parse().unwrap()
+        );
+        assert_eq!(Languages::All, "all".parse().unwrap());
+        assert!("c,cobol".parse::<Languages>().is_err());
+    }
+
+    fn check(output: &str, kind: CompilerKind, vendor: Option<&str>, version: (u32, u32, u32)) {
+        let identity = identify(output).unwrap();
+        assert_eq!(kind, identity.kind);
+        assert_eq!(vendor, identity.vendor.as_deref());
+        assert_eq!(
+            Version::new(version.0, version.1, version.2),
+            identity.version
+        );
+    }
+
+    #[test]
+    fn version_parsing() {
+        assert_eq!(Version::new(7, 2, 0), "7.2.0".parse().unwrap());
+        assert_eq!(Version::new(6, 0, 0), "6.0".parse().unwrap());
+        assert_eq!(Version::new(12, 0, 0), "12".parse().unwrap());
+        assert_eq!(Version::new(14, 0, 0), "14.0.0-1ubuntu1.1".parse().unwrap());
+        assert_eq!(Version::new(17, 0, 0), "17.0.0git".parse().unwrap());
+        assert!("git".parse::<Version>().is_err());
+        assert!(Version::new(4, 8, 5) < Version::new(4, 10, 0));
+        assert_eq!("7.2.0", Version::new(7, 2, 0).to_string());
+    }
+
+    #[test]
+    fn gcc_debian() {
+        check(
+            "Using built-in specs.\n\
+             COLLECT_GCC=gcc\n\
+             Target: x86_64-linux-gnu\n\
+             Thread model: posix\n\
+             Supported LTO compression algorithms: zlib zstd\n\
+             gcc version 12.2.0 (Debian 12.2.0-14)\n",
+            CompilerKind::Gcc,
+            None,
+            (12, 2, 0),
+        );
+    }
+
+    #[test]
+    fn gcc_prerelease() {
+        check(
+            "gcc version 8.0.0 20170716 (prerelease) (GCC) \n",
+            CompilerKind::Gcc,
+            None,
+            (8, 0, 0),
+        );
+        check(
+            "gcc version 4.8.5 20150623 (Red Hat 4.8.5-44) (GCC) \n",
+            CompilerKind::Gcc,
+            None,
+            (4, 8, 5),
+        );
+    }
+
+    #[test]
+    fn clang_upstream() {
+        check(
+            "clang version 6.0.0 (tags/RELEASE_600/final)\n\
+             Target: x86_64-unknown-linux-gnu\n",
+            CompilerKind::Clang,
+            None,
+            (6, 0, 0),
+        );
+        check(
+            "clang version 17.0.0git (https://github.com/llvm/llvm-project.git 1a2b3c)\n",
+            CompilerKind::Clang,
+            None,
+            (17, 0, 0),
+        );
+    }
+
+    #[test]
+    fn clang_vendors() {
+        check(
+            "Ubuntu clang version 15.0.7\n\
+             Target: x86_64-pc-linux-gnu\n",
+            CompilerKind::Clang,
+            Some("Ubuntu"),
+            (15, 0, 7),
+        );
+        check(
+            "Debian clang version 14.0.6\n",
+            CompilerKind::Clang,
+            Some("Debian"),
+            (14, 0, 6),
+        );
+        check(
+            "Apple clang version 14.0.0 (clang-1400.0.29.202)\n\
+             Target: arm64-apple-darwin22.1.0\n",
+            CompilerKind::Clang,
+            Some("Apple"),
+            (14, 0, 0),
+        );
+        check(
+            "AMD clang version 16.0.0 (CLANG: AOCC_4.1.0-Build#270 2023_07_10)\n",
+            CompilerKind::Clang,
+            Some("AMD"),
+            (16, 0, 0),
+        );
+        check(
+            "Intel(R) oneAPI DPC++/C++ Compiler 2023.1.0 (2023.1.0.20230320)\n\
+             Target: x86_64-unknown-linux-gnu\n",
+            CompilerKind::Clang,
+            Some("Intel(R) oneAPI"),
+            (2023, 1, 0),
+        );
+    }
+
+    #[test]
+    fn rustc_versions() {
+        check(
+            "rustc 1.75.0 (82e1608df 2023-12-21)\n\
+             binary: rustc\n\
+             host: x86_64-unknown-linux-gnu\n\
+             release: 1.75.0\n",
+            CompilerKind::Rustc,
+            None,
+            (1, 75, 0),
+        );
+        let info = identify(
+            "rustc 1.77.0-nightly (6ae4cfbbb 2024-01-17)\nhost: aarch64-unknown-linux-gnu\n",
+        )
+        .unwrap();
+        assert_eq!(Version::new(1, 77, 0), info.version);
+        assert_eq!(Some("aarch64-unknown-linux-gnu"), info.target.as_deref());
+
+        assert!(is_rustc("/usr/bin/rustc".as_ref()));
+        assert!(is_rustc("rustc-1.75".as_ref()));
+        assert!(!is_rustc("/usr/bin/gcc".as_ref()));
+    }
+
+    #[test]
+    fn target_from_verbose_output() {
+        let info =
+            identify("Target: aarch64-linux-gnu\ngcc version 10.2.1 20210110 (Debian 10.2.1-6)\n");
+        assert_eq!(Some("aarch64-linux-gnu"), info.unwrap().target.as_deref());
+    }
+
+    #[test]
+    fn gcc_macros() {
+        let info = identify_macros(
+            "#define __STDC__ 1\n\
+             #define __GNUC_PATCHLEVEL__ 0\n\
+             #define __GNUC__ 12\n\
+             #define __GNUC_MINOR__ 2\n\
+             #define __VERSION__ \"12.2.0\"\n\
+             #define __STDC_VERSION__ 201710L\n",
+        )
+        .unwrap();
+        assert_eq!(CompilerKind::Gcc, info.kind);
+        assert_eq!(None, info.vendor);
+        assert_eq!(Version::new(12, 2, 0), info.version);
+        assert_eq!(Some("gnu17"), info.default_std.as_deref());
+
+        let std = |version: &str| {
+            let macros = format!(
+                "#define __GNUC__ 15\n#define __STDC_VERSION__ {}\n",
+                version
+            );
+            identify_macros(&macros).unwrap().default_std
+        };
+        assert_eq!(Some("gnu2x"), std("202000L").as_deref());
+        assert_eq!(Some("gnu23"), std("202311L").as_deref());
+    }
+
+    #[test]
+    fn clang_macros() {
+        // Clang also defines the __GNUC__ family for compatibility.
+        let info = identify_macros(
+            "#define __GNUC__ 4\n\
+             #define __GNUC_MINOR__ 2\n\
+             #define __GNUC_PATCHLEVEL__ 1\n\
+             #define __STDC_VERSION__ 201112L\n\
+             #define __STRICT_ANSI__ 1\n\
+             #define __VERSION__ \"Ubuntu Clang 15.0.7\"\n\
+             #define __clang__ 1\n\
+             #define __clang_major__ 15\n\
+             #define __clang_minor__ 0\n\
+             #define __clang_patchlevel__ 7\n",
+        )
+        .unwrap();
+        assert_eq!(CompilerKind::Clang, info.kind);
+        assert_eq!(Some("Ubuntu"), info.vendor.as_deref());
+        assert_eq!(Version::new(15, 0, 7), info.version);
+        assert_eq!(Some("c11"), info.default_std.as_deref());
+
+        let info = identify_macros(
+            "#define __apple_build_version__ 14000029\n\
+             #define __clang__ 1\n\
+             #define __clang_major__ 14\n\
+             #define __clang_minor__ 0\n\
+             #define __clang_patchlevel__ 0\n\
+             #define __VERSION__ \"Apple LLVM 14.0.0 (clang-1400.0.29.202)\"\n",
+        )
+        .unwrap();
+        assert_eq!(Some("Apple"), info.vendor.as_deref());
+        assert_eq!(Version::new(14, 0, 0), info.version);
+        assert_eq!(None, info.default_std);
+    }
+
+    #[test]
+    fn unknown_macros() {
+        assert_eq!(None, identify_macros("#define __TINYC__ 927\n"));
+        assert_eq!(None, identify_macros("#define __GNUC_MINOR__ 2\n"));
+    }
+
+    #[test]
+    fn unknown_compiler() {
+        assert_eq!(None, identify("tcc version 0.9.27 (x86_64 Linux)\n"));
+        assert_eq!(None, identify(""));
+    }
+}
@@ -4,24 +4,110 @@
 // Distributed under terms of the MIT license.
 //
 
-use blake2_rfc::blake2b::{Blake2b, Blake2bResult};
+use blake2_rfc::blake2b::Blake2b;
+use md5::Md5;
+use sha2::{Digest, Sha256};
 use std::convert::{AsRef, From};
-use std::fmt::Write as FmtWrite;
-use std::io::{Result as IOResult, Write};
+use std::fmt::{self, Write as FmtWrite};
+use std::io::{Read, Result as IOResult, Write};
+use std::str::FromStr;
 
-#[derive(Eq, PartialEq)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Algorithm {
+    Blake2b,
+    Sha256,
+    Md5,
+}
+
+impl Algorithm {
+    pub const NAMES: &'static [&'static str] = &["blake2b", "sha256", "md5"];
+
+    pub fn digest<D: AsRef<[u8]>>(self, data: D) -> Checksum {
+        let mut hasher = Hasher::new(self);
+        hasher.update(data.as_ref());
+        hasher.finalize()
+    }
+
+    pub fn digest_reader<R: Read>(self, reader: &mut R) -> IOResult<Checksum> {
+        let mut writer = CSumWriter::with_algorithm(std::io::sink(), self);
+        std::io::copy(reader, &mut writer)?;
+        Ok(writer.into_inner().1)
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "blake2b" => Ok(Algorithm::Blake2b),
+            "sha256" => Ok(Algorithm::Sha256),
+            "md5" => Ok(Algorithm::Md5),
+            _ => Err(format!("unsupported hash algorithm: {}", s)),
+        }
+    }
+}
+
+enum Hasher {
+    Blake2b(Blake2b),
+    Sha256(Sha256),
+    Md5(Md5),
+}
+
+impl Hasher {
+    fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Blake2b => Hasher::Blake2b(Blake2b::new(64)),
+            Algorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            Algorithm::Md5 => Hasher::Md5(Md5::new()),
+        }
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake2b(h) => h.update(data),
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Md5(h) => h.update(data),
+        }
+    }
+
+    fn finalize(self) -> Checksum {
+        match self {
+            Hasher::Blake2b(h) => h.finalize().as_bytes().into(),
+            Hasher::Sha256(h) => h.finalize().as_slice().into(),
+            Hasher::Md5(h) => h.finalize().as_slice().into(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Checksum {
-    result: Blake2bResult,
+    bytes: Vec<u8>,
     hexstr: String,
 }
 
-impl From<Blake2bResult> for Checksum {
-    fn from(result: Blake2bResult) -> Self {
+impl Checksum {
+    pub fn of<D: AsRef<[u8]>>(data: D) -> Self {
+        Algorithm::Blake2b.digest(data)
+    }
+}
+
+impl<'a> From<&'a [u8]> for Checksum {
+    fn from(bytes: &'a [u8]) -> Self {
         let mut hexstr = String::new();
-        for byte in result.as_bytes() {
+        for byte in bytes {
             write!(hexstr, "{:02x}", byte).unwrap();
         }
-        Self { result, hexstr }
+        Self {
+            bytes: bytes.to_vec(),
+            hexstr,
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.hexstr)
     }
 }
 
@@ -39,25 +125,29 @@ impl AsRef<str> for Checksum {
 
 impl AsRef<[u8]> for Checksum {
     fn as_ref(&self) -> &[u8] {
-        self.result.as_bytes()
+        &self.bytes
     }
 }
 
 pub struct CSumWriter<W: Write> {
     inner: W,
-    csum: Blake2b,
+    csum: Hasher,
 }
 
 impl<W: Write> CSumWriter<W> {
     pub fn new(inner: W) -> Self {
+        Self::with_algorithm(inner, Algorithm::Blake2b)
+    }
+
+    pub fn with_algorithm(inner: W, algorithm: Algorithm) -> Self {
         Self {
             inner,
-            csum: Blake2b::new(64),
+            csum: Hasher::new(algorithm),
         }
     }
 
     pub fn into_inner(self) -> (W, Checksum) {
-        (self.inner, self.csum.finalize().into())
+        (self.inner, self.csum.finalize())
     }
 }
 
@@ -67,7 +157,45 @@ impl<W: Write> Write for CSumWriter<W> {
     }
 
     fn write(&mut self, data: &[u8]) -> IOResult<usize> {
-        self.csum.write_all(data)?;
-        self.inner.write(data)
+        let count = self.inner.write(data)?;
+        self.csum.update(&data[..count]);
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_digests() {
+        assert_eq!(
+            "d41d8cd98f00b204e9800998ecf8427e",
+            Algorithm::Md5.digest("").to_string()
+        );
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            Algorithm::Sha256.digest("abc").to_string()
+        );
+        assert_eq!(
+            concat!(
+                "ba80a53f981c4d0d6a2797b69f12f6e94c212f14685ac4b74b12bb6fdbffa2d1",
+                "7d87c5392aab792dc252d5de4533cc9518d38aa8dbf1925ab92386edd4009923"
+            ),
+            Algorithm::Blake2b.digest("abc").to_string()
+        );
+    }
+
+    #[test]
+    fn writer_matches_digest() {
+        let data = b"popsicle";
+        for name in Algorithm::NAMES {
+            let algorithm: Algorithm = name.parse().unwrap();
+            let mut writer = CSumWriter::with_algorithm(Vec::new(), algorithm);
+            writer.write_all(data).unwrap();
+            let (inner, csum) = writer.into_inner();
+            assert_eq!(&inner[..], &data[..]);
+            assert_eq!(algorithm.digest(data), csum);
+        }
     }
 }
@@ -0,0 +1,322 @@
+//
+// graph.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::collections::{HashMap, HashSet};
+use std::io::{Result as IoResult, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::bindep::Solver;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Format {
+    Dot,
+    Tree,
+    Json,
+}
+
+impl Format {
+    pub const NAMES: &'static [&'static str] = &["dot", "tree", "json"];
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(Format::Dot),
+            "tree" => Ok(Format::Tree),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unsupported graph format: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Node {
+    path: PathBuf,
+    size: u64,
+    children: Vec<usize>,
+}
+
+/// Dependency graph of the packaged files, from each file to the files
+/// needed to run it (shared libraries and script interpreters). Files
+/// which were added without being scanned, like configuration files or
+/// sysroot contents, are roots without dependencies.
+#[derive(Debug)]
+pub struct Graph {
+    nodes: Vec<Node>,
+    roots: Vec<usize>,
+}
+
+impl Graph {
+    pub fn from_solver(solver: &Solver) -> Self {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            roots: Vec::new(),
+        };
+        let mut index = HashMap::new();
+        for root in solver.roots() {
+            let root = graph.add(solver, &mut index, root);
+            graph.roots.push(root);
+        }
+        for entry in solver.entries() {
+            if let Some(source) = entry.source() {
+                if !index.contains_key(source) {
+                    let root = graph.add(solver, &mut index, source);
+                    graph.roots.push(root);
+                }
+            }
+        }
+        graph
+    }
+
+    fn add(&mut self, solver: &Solver, index: &mut HashMap<PathBuf, usize>, path: &Path) -> usize {
+        if let Some(&node) = index.get(path) {
+            return node;
+        }
+        let node = self.nodes.len();
+        index.insert(path.to_path_buf(), node);
+        self.nodes.push(Node {
+            path: path.to_path_buf(),
+            size: path.metadata().map(|m| m.len()).unwrap_or(0),
+            children: Vec::new(),
+        });
+        let children = solver
+            .dependencies(path)
+            .iter()
+            .map(|child| self.add(solver, index, child))
+            .collect();
+        self.nodes[node].children = children;
+        node
+    }
+
+    /// Size of a node plus the sizes of everything reachable from it,
+    /// counting shared dependencies once.
+    fn total_size(&self, node: usize) -> u64 {
+        let mut seen = HashSet::new();
+        let mut pending = vec![node];
+        let mut total = 0;
+        while let Some(node) = pending.pop() {
+            if seen.insert(node) {
+                total += self.nodes[node].size;
+                pending.extend(&self.nodes[node].children);
+            }
+        }
+        total
+    }
+
+    pub fn write<W: Write>(&self, format: Format, out: &mut W) -> IoResult<()> {
+        match format {
+            Format::Dot => self.write_dot(out),
+            Format::Tree => self.write_tree(out),
+            Format::Json => self.write_json(out),
+        }
+    }
+
+    fn write_dot<W: Write>(&self, out: &mut W) -> IoResult<()> {
+        writeln!(out, "digraph popsicle {{")?;
+        writeln!(out, "    node [shape=box];")?;
+        for (id, node) in self.nodes.iter().enumerate() {
+            writeln!(
+                out,
+                "    n{} [label=\"{}\\n{} ({} total)\"];",
+                id,
+                node.path.display().to_string().replace('"', "\\\""),
+                human_size(node.size),
+                human_size(self.total_size(id))
+            )?;
+        }
+        for (id, node) in self.nodes.iter().enumerate() {
+            for child in &node.children {
+                writeln!(out, "    n{} -> n{};", id, child)?;
+            }
+        }
+        writeln!(out, "}}")
+    }
+
+    fn write_tree<W: Write>(&self, out: &mut W) -> IoResult<()> {
+        let mut printed = HashSet::new();
+        for &root in &self.roots {
+            self.write_tree_node(out, root, "", "", &mut printed)?;
+        }
+        Ok(())
+    }
+
+    fn write_tree_node<W: Write>(
+        &self,
+        out: &mut W,
+        id: usize,
+        prefix: &str,
+        child_prefix: &str,
+        printed: &mut HashSet<usize>,
+    ) -> IoResult<()> {
+        let node = &self.nodes[id];
+        write!(
+            out,
+            "{}{} [{}",
+            prefix,
+            node.path.display(),
+            human_size(node.size)
+        )?;
+        if node.children.is_empty() {
+            write!(out, "]")?;
+        } else {
+            write!(out, ", {} total]", human_size(self.total_size(id)))?;
+        }
+        // Dependencies of nodes already shown are not repeated.
+        if !printed.insert(id) {
+            if !node.children.is_empty() {
+                write!(out, " (*)")?;
+            }
+            return writeln!(out);
+        }
+        writeln!(out)?;
+
+        // Heaviest subtrees first.
+        let mut children = node.children.clone();
+        children.sort_by_key(|&child| std::cmp::Reverse(self.total_size(child)));
+        for (n, &child) in children.iter().enumerate() {
+            let (branch, next) = if n + 1 == children.len() {
+                ("└── ", "    ")
+            } else {
+                ("├── ", "│   ")
+            };
+            self.write_tree_node(
+                out,
+                child,
+                &format!("{}{}", child_prefix, branch),
+                &format!("{}{}", child_prefix, next),
+                printed,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn write_json<W: Write>(&self, out: &mut W) -> IoResult<()> {
+        let path = |id: usize| json_string(&self.nodes[id].path.display().to_string());
+        let roots: Vec<String> = self.roots.iter().map(|&id| path(id)).collect();
+        writeln!(out, "{{")?;
+        writeln!(out, "  \"roots\": [{}],", roots.join(", "))?;
+        writeln!(out, "  \"nodes\": [")?;
+        for (id, node) in self.nodes.iter().enumerate() {
+            let children: Vec<String> = node.children.iter().map(|&child| path(child)).collect();
+            writeln!(
+                out,
+                "    {{\"path\": {}, \"size\": {}, \"total_size\": {}, \"dependencies\": [{}]}}{}",
+                path(id),
+                node.size,
+                self.total_size(id),
+                children.join(", "),
+                if id + 1 == self.nodes.len() { "" } else { "," }
+            )?;
+        }
+        writeln!(out, "  ]")?;
+        writeln!(out, "}}")
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut result = String::with_capacity(s.len() + 2);
+    result.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("\\\""),
+            '\\' => result.push_str("\\\\"),
+            '\n' => result.push_str("\\n"),
+            c if (c as u32) < 0x20 => result.push_str(&format!("\\u{:04x}", c as u32)),
+            c => result.push(c),
+        }
+    }
+    result.push('"');
+    result
+}
+
+fn human_size(size: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB"];
+    if size < 1024 {
+        return format!("{} B", size);
+    }
+    let mut value = size as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < UNITS.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds a graph by hand: a -> {b, c}, b -> c.
+    fn graph() -> Graph {
+        let node = |path: &str, size, children| Node {
+            path: PathBuf::from(path),
+            size,
+            children,
+        };
+        Graph {
+            nodes: vec![
+                node("/bin/a", 100, vec![1, 2]),
+                node("/lib/b", 2048, vec![2]),
+                node("/lib/c", 10, vec![]),
+            ],
+            roots: vec![0],
+        }
+    }
+
+    fn output(format: Format) -> String {
+        let mut out = Vec::new();
+        graph().write(format, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn total_sizes() {
+        let graph = graph();
+        assert_eq!(2158, graph.total_size(0));
+        assert_eq!(2058, graph.total_size(1));
+        assert_eq!(10, graph.total_size(2));
+    }
+
+    #[test]
+    fn tree_format() {
+        assert_eq!(
+            "/bin/a [100 B, 2.1 KiB total]\n\
+             ├── /lib/b [2.0 KiB, 2.0 KiB total]\n\
+             │   └── /lib/c [10 B]\n\
+             └── /lib/c [10 B]\n",
+            output(Format::Tree)
+        );
+    }
+
+    #[test]
+    fn dot_format() {
+        let dot = output(Format::Dot);
+        assert!(dot.starts_with("digraph popsicle {\n"));
+        assert!(dot.contains("    n1 [label=\"/lib/b\\n2.0 KiB (2.0 KiB total)\"];\n"));
+        assert!(dot.contains("    n0 -> n1;\n    n0 -> n2;\n    n1 -> n2;\n"));
+    }
+
+    #[test]
+    fn json_format() {
+        let json = output(Format::Json);
+        assert!(json.contains("\"roots\": [\"/bin/a\"],"));
+        assert!(json.contains(
+            "{\"path\": \"/lib/b\", \"size\": 2048, \"total_size\": 2058, \"dependencies\": [\"/lib/c\"]},"
+        ));
+        assert_eq!("\"a\\\"b\\\\c\"", json_string("a\"b\\c"));
+    }
+
+    #[test]
+    fn human_sizes() {
+        assert_eq!("0 B", human_size(0));
+        assert_eq!("1.5 KiB", human_size(1536));
+        assert_eq!("40.0 MiB", human_size(40 * 1024 * 1024));
+    }
+}
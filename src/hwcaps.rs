@@ -0,0 +1,190 @@
+//
+// hwcaps.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use goblin::elf::Elf;
+use log::{debug, info};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+/// Levels of the "glibc-hwcaps" subdirectories for each architecture, from
+/// the baseline up.
+const LEVELS: &[&[&str]] = &[
+    &["x86-64-v2", "x86-64-v3", "x86-64-v4"],
+    &["power9", "power10"],
+    &["z13", "z14", "z15", "z16"],
+];
+
+/// Which subdirectories of the library search directories, with libraries
+/// optimized for particular CPUs, are searched.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Hwcaps {
+    /// The ones the dynamic loader of the host searches.
+    Host,
+    /// None of them, only the baseline libraries are used.
+    None,
+    /// The "glibc-hwcaps" subdirectories up to the given level, for build
+    /// farms where some nodes support less than the host.
+    Level(&'static str),
+}
+
+impl Hwcaps {
+    pub const NAMES: &'static [&'static str] = &[
+        "host",
+        "none",
+        "x86-64-v2",
+        "x86-64-v3",
+        "x86-64-v4",
+        "power9",
+        "power10",
+        "z13",
+        "z14",
+        "z15",
+        "z16",
+    ];
+
+    /// Subdirectories to search in each library directory, in priority
+    /// order.
+    pub fn subdirs(self) -> Vec<PathBuf> {
+        match self {
+            Hwcaps::Host => host_subdirs(),
+            Hwcaps::None => vec![],
+            Hwcaps::Level(level) => {
+                let levels = LEVELS
+                    .iter()
+                    .find(|levels| levels.contains(&level))
+                    .unwrap();
+                let count = levels.iter().position(|&l| l == level).unwrap() + 1;
+                levels[..count]
+                    .iter()
+                    .rev()
+                    .map(|level| Path::new("glibc-hwcaps").join(level))
+                    .collect()
+            }
+        }
+    }
+}
+
+impl FromStr for Hwcaps {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "host" => Ok(Hwcaps::Host),
+            "none" => Ok(Hwcaps::None),
+            _ => LEVELS
+                .iter()
+                .flat_map(|levels| levels.iter())
+                .find(|&&level| level == s)
+                .map(|&level| Hwcaps::Level(level))
+                .ok_or_else(|| format!("unsupported hwcaps level: {}", s)),
+        }
+    }
+}
+
+/// Asks the dynamic loader used by popsicle itself which subdirectories it
+/// searches on this host.
+fn host_subdirs() -> Vec<PathBuf> {
+    let loader = std::env::current_exe()
+        .and_then(std::fs::read)
+        .ok()
+        .and_then(|data| Elf::parse(&data).ok()?.interpreter.map(PathBuf::from));
+    let loader = match loader {
+        Some(loader) => loader,
+        None => {
+            info!("cannot find the dynamic loader, hwcaps subdirectories not searched");
+            return vec![];
+        }
+    };
+    match Command::new(&loader)
+        .arg("--help")
+        .env("LC_ALL", "C")
+        .output()
+    {
+        Ok(ref output) if output.status.success() => {
+            let subdirs = parse_loader_help(&String::from_utf8_lossy(&output.stdout));
+            debug!("hwcaps subdirectories: {:?}", subdirs);
+            subdirs
+        }
+        _ => {
+            info!("{:?} does not list hwcaps subdirectories", loader);
+            vec![]
+        }
+    }
+}
+
+/// Obtains the searched subdirectories from the output of "ld.so --help",
+/// as printed by glibc 2.33 and later.
+fn parse_loader_help(output: &str) -> Vec<PathBuf> {
+    let mut subdirs = Vec::new();
+    let mut prefix = None;
+    for line in output.lines() {
+        if line.starts_with("Subdirectories of glibc-hwcaps directories") {
+            prefix = Some(Path::new("glibc-hwcaps"));
+        } else if line.starts_with("Legacy HWCAP subdirectories") {
+            prefix = Some(Path::new(""));
+        } else if !line.starts_with(' ') {
+            prefix = None;
+        } else if let Some(prefix) = prefix {
+            if line.contains("searched)") {
+                if let Some(name) = line.split_whitespace().next() {
+                    subdirs.push(prefix.join(name));
+                }
+            }
+        }
+    }
+    subdirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loader_help_parsing() {
+        let output = "\
+Usage: ld.so [OPTION]... EXECUTABLE-FILE [ARGS-FOR-PROGRAM...]
+
+Subdirectories of glibc-hwcaps directories, in priority order:
+  x86-64-v4
+  x86-64-v3 (supported, searched)
+  x86-64-v2 (supported, searched)
+
+Legacy HWCAP subdirectories under library search path directories:
+  haswell (AT_PLATFORM; supported, searched)
+  avx512_1
+  x86_64 (supported, searched)
+";
+        let subdirs: Vec<PathBuf> = [
+            "glibc-hwcaps/x86-64-v3",
+            "glibc-hwcaps/x86-64-v2",
+            "haswell",
+            "x86_64",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(subdirs, parse_loader_help(output));
+        assert!(parse_loader_help("Usage: ld.so [OPTION]...\n").is_empty());
+    }
+
+    #[test]
+    fn pinned_levels() {
+        let hwcaps: Hwcaps = "x86-64-v3".parse().unwrap();
+        assert_eq!(
+            vec![
+                PathBuf::from("glibc-hwcaps/x86-64-v3"),
+                PathBuf::from("glibc-hwcaps/x86-64-v2"),
+            ],
+            hwcaps.subdirs()
+        );
+        assert!(Hwcaps::None.subdirs().is_empty());
+        for name in Hwcaps::NAMES {
+            assert!(name.parse::<Hwcaps>().is_ok());
+        }
+        assert!("x86-64-v9".parse::<Hwcaps>().is_err());
+    }
+}
@@ -0,0 +1,231 @@
+//
+// layout.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+use crate::bindep::Entry;
+use crate::csum::{Algorithm, Checksum};
+
+/// How host paths are arranged inside the tarball.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Layout {
+    /// Collapse "/usr" onto "/" and "/sbin" onto "/bin", using symbolic
+    /// links. This matches merged-usr hosts, where e.g. "/lib/libc.so.6" and
+    /// "/usr/lib/libc.so.6" are the same file.
+    Merged,
+    /// Keep every file at its host path. Needed on split-usr hosts where
+    /// "/lib" and "/usr/lib" contain different files with the same name.
+    Split,
+}
+
+impl Layout {
+    pub const NAMES: &'static [&'static str] = &["merged", "split"];
+
+    /// Symbolic links created in the tarball, as (target, path) pairs.
+    pub fn symlinks(self) -> &'static [(&'static str, &'static str)] {
+        match self {
+            Layout::Merged => &[("bin", "sbin"), (".", "usr")],
+            Layout::Split => &[],
+        }
+    }
+
+    /// Path inside the tarball for a path, which can be relative to the
+    /// root of the tarball or absolute. Leading components which are
+    /// symbolic links of the layout are resolved, so every file has exactly
+    /// one location.
+    pub fn place<P: AsRef<Path>>(self, path: P) -> PathBuf {
+        let mut path = path.as_ref().to_path_buf();
+        if let Ok(relative) = path.strip_prefix("/") {
+            path = relative.to_path_buf();
+        }
+        loop {
+            let first = match path.components().next() {
+                Some(Component::Normal(first)) => PathBuf::from(first),
+                _ => return path,
+            };
+            let target = self
+                .symlinks()
+                .iter()
+                .find(|(_, link)| Path::new(link) == first)
+                .map(|(target, _)| target);
+            match target {
+                Some(target) if path != first => {
+                    let rest = path.strip_prefix(&first).unwrap();
+                    path = match *target {
+                        "." => rest.to_path_buf(),
+                        target => Path::new(target).join(rest),
+                    };
+                }
+                _ => return path,
+            }
+        }
+    }
+}
+
+impl FromStr for Layout {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merged" => Ok(Layout::Merged),
+            "split" => Ok(Layout::Split),
+            _ => Err(format!("unsupported layout: {}", s)),
+        }
+    }
+}
+
+/// Two entries which would be written at the same location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collision {
+    pub path: PathBuf,
+    pub existing: String,
+    pub rejected: String,
+}
+
+impl fmt::Display for Collision {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} conflicts with {}",
+            self.path.display(),
+            self.rejected,
+            self.existing
+        )
+    }
+}
+
+/// Digests of host files, each one calculated at most once, used to find
+/// files with the same contents.
+#[derive(Debug, Default)]
+pub struct Digests {
+    digests: RefCell<HashMap<PathBuf, Option<Checksum>>>,
+}
+
+impl Digests {
+    fn get(&self, path: &Path) -> Option<Checksum> {
+        self.digests
+            .borrow_mut()
+            .entry(path.to_path_buf())
+            .or_insert_with(|| {
+                let mut file = std::fs::File::open(path).ok()?;
+                Algorithm::Blake2b.digest_reader(&mut file).ok()
+            })
+            .clone()
+    }
+
+    /// Whether two host files have the same contents.
+    pub fn same_file(&self, a: &Path, b: &Path) -> bool {
+        if a == b {
+            return true;
+        }
+        match (a.metadata(), b.metadata()) {
+            (Ok(a_meta), Ok(b_meta)) => {
+                if (a_meta.dev(), a_meta.ino()) == (b_meta.dev(), b_meta.ino()) {
+                    return true;
+                }
+                if a_meta.len() != b_meta.len() {
+                    return false;
+                }
+                match (self.get(a), self.get(b)) {
+                    (Some(a_digest), Some(b_digest)) => a_digest == b_digest,
+                    _ => false,
+                }
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Whether two entries placed at the same location would result in the
+/// same contents, in which case only one of them needs to be written.
+pub fn is_duplicate(a: &Entry, b: &Entry, digests: &Digests) -> bool {
+    match (a, b) {
+        (a, b) if a.source().is_some() && b.source().is_some() => {
+            digests.same_file(a.source().unwrap(), b.source().unwrap())
+        }
+        (Entry::Symlink { target: a, .. }, Entry::Symlink { target: b, .. }) => a == b,
+        (Entry::Data { data: a, .. }, Entry::Data { data: b, .. }) => a == b,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn merged_placement() {
+        let layout = Layout::Merged;
+        assert_eq!(
+            PathBuf::from("lib/libc.so.6"),
+            layout.place("/usr/lib/libc.so.6")
+        );
+        assert_eq!(
+            PathBuf::from("lib/libc.so.6"),
+            layout.place("/lib/libc.so.6")
+        );
+        assert_eq!(
+            PathBuf::from("bin/ldconfig"),
+            layout.place("/usr/sbin/ldconfig")
+        );
+        assert_eq!(
+            PathBuf::from("bin/clang++"),
+            layout.place("usr/bin/clang++")
+        );
+        assert_eq!(
+            PathBuf::from("opt/usr/bin/as"),
+            layout.place("/opt/usr/bin/as")
+        );
+        // The links themselves are not resolved.
+        assert_eq!(PathBuf::from("usr"), layout.place("usr"));
+        assert_eq!(PathBuf::from("sbin"), layout.place("/sbin"));
+    }
+
+    #[test]
+    fn split_placement() {
+        let layout = Layout::Split;
+        assert_eq!(
+            PathBuf::from("usr/lib/libc.so.6"),
+            layout.place("/usr/lib/libc.so.6")
+        );
+        assert_eq!(
+            PathBuf::from("sbin/ldconfig"),
+            layout.place("/sbin/ldconfig")
+        );
+        assert!(layout.symlinks().is_empty());
+    }
+
+    #[test]
+    fn digests_are_reused() {
+        let dir = tempdir::TempDir::new("layout").unwrap();
+        let write = |name: &str, data: &[u8]| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, data).unwrap();
+            path
+        };
+        let foo = write("foo", b"foo");
+        let foo_copy = write("foo-copy", b"foo");
+        let bar = write("bar", b"bar");
+        let digests = Digests::default();
+        assert!(digests.same_file(&foo, &foo_copy));
+        assert!(!digests.same_file(&foo, &bar));
+        assert!(!digests.same_file(&foo_copy, &bar));
+        assert_eq!(3, digests.digests.borrow().len());
+    }
+
+    #[test]
+    fn layout_parsing() {
+        for name in Layout::NAMES {
+            assert!(name.parse::<Layout>().is_ok());
+        }
+        assert!("flat".parse::<Layout>().is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//
+// ldconfig.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use lazy_static::lazy_static;
+use log::{debug, warn};
+use std::path::{Path, PathBuf};
+
+const LD_SO_CONF: &str = "/etc/ld.so.conf";
+
+/// Limit for nested "include" directives, in case of loops.
+const MAX_DEPTH: usize = 8;
+
+lazy_static! {
+    static ref DIRS: Vec<PathBuf> = {
+        let mut dirs = Vec::new();
+        read(Path::new(LD_SO_CONF), &mut dirs, 0);
+        debug!("{} directories: {:?}", LD_SO_CONF, dirs);
+        dirs
+    };
+}
+
+/// Directories listed in the configuration of ldconfig, whose libraries
+/// the dynamic loader finds through its cache before the default ones.
+pub fn dirs() -> &'static [PathBuf] {
+    &DIRS
+}
+
+fn read(path: &Path, dirs: &mut Vec<PathBuf>, depth: usize) {
+    match std::fs::read_to_string(path) {
+        Ok(contents) => parse(path, &contents, dirs, depth),
+        Err(e) => debug!("cannot read {:?}: {}", path, e),
+    }
+}
+
+/// Parses the contents of an ld.so.conf file, following its includes.
+fn parse(path: &Path, contents: &str, dirs: &mut Vec<PathBuf>, depth: usize) {
+    let base = path.parent().unwrap_or_else(|| Path::new("/"));
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap().trim();
+        let mut words = line.split_whitespace();
+        match words.next() {
+            None | Some("hwcap") => {}
+            Some("include") if depth < MAX_DEPTH => {
+                for pattern in words {
+                    for included in glob(&base.join(pattern)) {
+                        read(&included, dirs, depth + 1);
+                    }
+                }
+            }
+            Some("include") => warn!("too many nested includes in {:?}", path),
+            // Like ldconfig, accept several directories per line, and skip
+            // the library type of legacy "dir=TYPE" entries.
+            Some(_) => {
+                for dir in line.split([':', ',', ' ', '\t']).filter(|d| !d.is_empty()) {
+                    let dir = PathBuf::from(dir.split('=').next().unwrap());
+                    if dir.is_absolute() && !dirs.contains(&dir) {
+                        dirs.push(dir);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Expands wildcards in the last component of a path, as used by the
+/// "include" directive.
+fn glob(pattern: &Path) -> Vec<PathBuf> {
+    let (dir, name) = match (
+        pattern.parent(),
+        pattern.file_name().and_then(|n| n.to_str()),
+    ) {
+        (Some(dir), Some(name)) if name.contains(['*', '?']) => (dir, name),
+        _ => return vec![pattern.to_path_buf()],
+    };
+    let mut paths: Vec<PathBuf> = match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_name().to_str().is_some_and(|n| matches(name, n)))
+            .map(|entry| entry.path())
+            .collect(),
+        Err(_) => vec![],
+    };
+    paths.sort();
+    paths
+}
+
+/// Matches a file name against a pattern with "*" and "?" wildcards.
+fn matches(pattern: &str, name: &str) -> bool {
+    match pattern.chars().next() {
+        None => name.is_empty(),
+        Some('*') => (0..=name.len())
+            .filter(|&i| name.is_char_boundary(i))
+            .any(|i| matches(&pattern[1..], &name[i..])),
+        Some(c) => {
+            let mut chars = name.chars();
+            match chars.next() {
+                Some(n) if c == '?' || c == n => matches(&pattern[c.len_utf8()..], chars.as_str()),
+                _ => false,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn wildcards() {
+        assert!(matches("*.conf", "libc.conf"));
+        assert!(matches("x86_64-*.conf", "x86_64-linux-gnu.conf"));
+        assert!(matches("lib?.conf", "libc.conf"));
+        assert!(!matches("*.conf", "libc.conf.dpkg-old"));
+        assert!(!matches("lib?.conf", "lib.conf"));
+    }
+
+    #[test]
+    fn includes_are_followed() {
+        let dir = TempDir::new("ldconfig").unwrap();
+        let conf_d = dir.path().join("ld.so.conf.d");
+        std::fs::create_dir(&conf_d).unwrap();
+        std::fs::write(
+            conf_d.join("x86_64-linux-gnu.conf"),
+            "# Multiarch support\n/usr/local/lib/x86_64-linux-gnu\n/lib/x86_64-linux-gnu\n",
+        )
+        .unwrap();
+        std::fs::write(conf_d.join("libc.conf"), "/usr/local/lib\n").unwrap();
+        std::fs::write(conf_d.join("README"), "/not/included\n").unwrap();
+        let conf = dir.path().join("ld.so.conf");
+        std::fs::write(
+            &conf,
+            "include ld.so.conf.d/*.conf\nhwcap 0 nosegneg\n/opt/lib64 # local\n/usr/local/lib\n\
+             /opt/a /opt/b:/opt/c,\t/opt/d\n/usr/X11R6/lib=libc5\n",
+        )
+        .unwrap();
+
+        let mut dirs = Vec::new();
+        read(&conf, &mut dirs, 0);
+        let expected: Vec<PathBuf> = [
+            "/usr/local/lib",
+            "/usr/local/lib/x86_64-linux-gnu",
+            "/lib/x86_64-linux-gnu",
+            "/opt/lib64",
+            "/opt/a",
+            "/opt/b",
+            "/opt/c",
+            "/opt/d",
+            "/usr/X11R6/lib",
+        ]
+        .iter()
+        .map(PathBuf::from)
+        .collect();
+        assert_eq!(expected, dirs);
+    }
+}
@@ -12,8 +12,18 @@ use pretty_assertions::assert_eq;
 
 mod bindep;
 mod cache;
+mod compiler;
 mod csum;
 mod errors;
+mod graph;
+mod hwcaps;
+mod layout;
+mod ldconfig;
+mod manifest;
+mod rustc;
+mod sccache;
+mod strip;
+mod sysroot;
 mod util;
 
 use libflate::gzip;
@@ -24,19 +34,141 @@ use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 use crate::bindep::TarBuilderExt;
+use crate::compiler::{CompilerKind, Language, Languages, Version};
 use crate::errors::*;
+use error_chain::bail;
 error_chain::quick_main!(run);
 
-fn compiler_binaries<P: AsRef<Path>>(
-    compiler_kind: util::CompilerKind,
-    compiler_path: P,
-) -> Option<Vec<PathBuf>> {
-    match compiler_kind {
-        util::CompilerKind::Gcc => compiler_binaries_gcc(compiler_path.as_ref()),
-        util::CompilerKind::Clang => compiler_binaries_clang(compiler_path.as_ref()),
+fn compiler_binaries(toolchain: &Toolchain, options: &SolveOptions) -> Result<Vec<PathBuf>> {
+    let (compiler_path, version) = (&toolchain.compiler_path, &toolchain.version);
+    match toolchain.kind {
+        CompilerKind::Gcc => {
+            compiler_binaries_gcc(compiler_path, version, options.languages.as_ref())
+        }
+        CompilerKind::Clang => compiler_binaries_clang(compiler_path, version),
+        CompilerKind::Rustc => compiler_binaries_rustc(toolchain),
     }
 }
 
+#[inline]
+fn compiler_print_prog_name(compiler_path: &Path, prog_name: &str) -> Option<PathBuf> {
+    let output = match std::process::Command::new(compiler_path)
+        .arg(format!("-print-prog-name={}", prog_name))
+        .output()
+    {
+        Ok(out) => out,
+        Err(err) => {
+            warn!("could not run compiler {:?}: {}", compiler_path, err);
+            return None;
+        }
+    };
+
+    // The driver prints the bare program name when it would search $PATH,
+    // prefer the one installed alongside the compiler in that case.
+    let path = std::str::from_utf8(output.stdout.as_slice()).ok()?.trim();
+    if path == prog_name {
+        let sibling = compiler_path.with_file_name(prog_name);
+        if sibling.is_file() {
+            return Some(sibling);
+        }
+        return util::find_program(prog_name, None).ok();
+    }
+
+    let path: PathBuf = path.into();
+    if path.is_absolute() && path.is_file() {
+        Some(path)
+    } else {
+        None
+    }
+}
+
+/// Finds the assembler used by the driver. When the assembler is a wrapper
+/// script which runs one of the LLVM tools, those are included as well.
+fn compiler_assembler(compiler_path: &Path) -> Result<Vec<PathBuf>> {
+    let assembler_path = match compiler_print_prog_name(compiler_path, "as") {
+        Some(path) => path,
+        None => bail!("cannot find assembler executable"),
+    };
+    info!("Assembler executable: {:?}", assembler_path);
+
+    let head = util::read_head(&assembler_path, 2)
+        .chain_err(|| format!("cannot read {:?}", assembler_path))?;
+    let mut path_list = vec![assembler_path.clone()];
+    if head == b"#!" {
+        let script = std::fs::read(&assembler_path)
+            .chain_err(|| format!("cannot read {:?}", assembler_path))?;
+        let commands = script_commands(&String::from_utf8_lossy(&script));
+        for program in &["llvm-mc", "llvm-as"] {
+            if !commands.iter().any(|command| command == program) {
+                continue;
+            }
+            let sibling = assembler_path.with_file_name(program);
+            if sibling.is_file() {
+                path_list.push(sibling);
+            } else if let Ok(path) = util::find_program(program, None) {
+                path_list.push(path);
+            }
+        }
+    }
+    Ok(path_list)
+}
+
+/// Names of the programs which a shell script runs, e.g. "llvm-mc" for a
+/// line with "exec /usr/bin/llvm-mc -assemble". Comments are skipped.
+fn script_commands(script: &str) -> Vec<String> {
+    script
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split('#').next()?.split_whitespace();
+            match words.next()? {
+                "exec" => words.next(),
+                word => Some(word),
+            }
+        })
+        .filter_map(|word| {
+            let path = Path::new(word.trim_matches(|c| c == '"' || c == '\''));
+            Some(path.file_name()?.to_str()?.to_string())
+        })
+        .collect()
+}
+
+/// Checks whether Clang uses its integrated assembler by default, by
+/// looking for an assembler in the list of jobs it would run.
+fn clang_uses_integrated_as(compiler_path: &Path) -> bool {
+    let output = match std::process::Command::new(compiler_path)
+        .args(["-###", "-c", "-x", "c", "/dev/null", "-o", "/dev/null"])
+        .output()
+    {
+        Ok(out) => out,
+        Err(err) => {
+            warn!("could not run compiler {:?}: {}", compiler_path, err);
+            return false;
+        }
+    };
+
+    // Jobs are printed one per line, with quoted arguments.
+    let jobs = String::from_utf8_lossy(&output.stderr);
+    !jobs
+        .lines()
+        .filter_map(|line| line.trim_start().strip_prefix('"')?.split('"').next())
+        .filter_map(|program| Path::new(program).file_name()?.to_str())
+        .any(|name| name == "as" || name.ends_with("-as"))
+}
+
+fn compiler_driver_tools(compiler_path: &Path, tools: &[&str]) -> Vec<PathBuf> {
+    let mut path_list = Vec::new();
+    for tool in tools {
+        match compiler_print_prog_name(compiler_path, tool) {
+            Some(path) => {
+                info!("{} executable: {:?}", tool, path);
+                path_list.push(path);
+            }
+            None => debug!("{} executable not found, skipping", tool),
+        }
+    }
+    path_list
+}
+
 #[inline]
 fn compiler_print_file_name(compiler_path: &Path, file_name: &str) -> Option<PathBuf> {
     let output = match std::process::Command::new(compiler_path)
@@ -51,9 +183,7 @@ fn compiler_print_file_name(compiler_path: &Path, file_name: &str) -> Option<Pat
         }
     };
 
-    let path = std::str::from_utf8(output.stdout.as_slice())
-        .unwrap()
-        .trim();
+    let path = std::str::from_utf8(output.stdout.as_slice()).ok()?.trim();
     if path == file_name {
         return None;
     }
@@ -66,69 +196,592 @@ fn compiler_print_file_name(compiler_path: &Path, file_name: &str) -> Option<Pat
     }
 }
 
-// TODO: Propagate errors instead of panicing!
 #[inline]
-fn compiler_binaries_gcc(compiler_path: &Path) -> Option<Vec<PathBuf>> {
-    let mut path_list = Vec::new();
+fn compiler_binaries_gcc(
+    compiler_path: &Path,
+    version: &Version,
+    languages: Option<&Languages>,
+) -> Result<Vec<PathBuf>> {
+    // GCC always emits assembly code, so an assembler is always needed.
+    let mut path_list = compiler_assembler(compiler_path)?;
 
-    // cc1 is always needed to compile C code.
-    path_list.push(compiler_print_file_name(compiler_path, "cc1").unwrap()); // FIXME: panic!
+    // Front-ends of the languages enabled in this installation.
+    let front_ends: Vec<(Language, PathBuf)> = Language::ALL
+        .iter()
+        .filter_map(|&language| {
+            compiler_print_file_name(compiler_path, language.front_end())
+                .map(|path| (language, path))
+        })
+        .collect();
+    let names: Vec<&str> = front_ends
+        .iter()
+        .map(|(language, _)| language.name())
+        .collect();
+    info!("GCC languages: {}", names.join(", "));
+
+    // cc1 is always needed to compile C code, and to preprocess others.
+    match front_ends
+        .iter()
+        .find(|(language, _)| *language == Language::C)
+    {
+        Some((_, cc1)) => path_list.push(cc1.clone()),
+        None => bail!(ErrorKind::CompilerInfoError("cannot find cc1")),
+    }
 
     // The LTO plug-in may (or may not) be available.
     if let Some(lto_plugin) = compiler_print_file_name(compiler_path, "liblto_plugin.so") {
         path_list.push(lto_plugin);
     }
 
-    // C++ support is optional in GCC.
-    if let Some(cc1plus) = compiler_print_file_name(compiler_path, "cc1plus") {
-        path_list.push(cc1plus);
-        // This means that the g++ executable must be around as well.
-        let mut gxx = compiler_path.to_path_buf();
-        gxx.set_file_name("g++");
-        if gxx.is_file() {
-            path_list.push(gxx);
-        } else {
-            path_list.push(util::find_program("g++", None).unwrap()); // FIXME: panic!
+    // Languages other than C are optional in GCC, and only those which were
+    // explicitly listed must be installed.
+    let (selected, required) = match languages {
+        None => (&[Language::C, Language::Cxx][..], false),
+        Some(Languages::All) => (Language::ALL, false),
+        Some(Languages::List(languages)) => (&languages[..], true),
+    };
+    for &language in selected {
+        if language == Language::C {
+            continue;
+        }
+        match front_ends.iter().find(|(l, _)| *l == language) {
+            Some((_, front_end)) => path_list.push(front_end.clone()),
+            None if required => bail!(
+                "cannot find {}, GCC does not support {}",
+                language.front_end(),
+                language.name()
+            ),
+            None => {
+                debug!("{} not found, skipping", language.front_end());
+                continue;
+            }
+        }
+        // The driver for the language must be around as well.
+        if let Some(driver) = language.driver() {
+            let sibling = compiler_path.with_file_name(driver);
+            if sibling.is_file() {
+                path_list.push(sibling);
+            } else {
+                path_list.push(util::find_program(driver, None)?);
+            }
+        }
+    }
+
+    // Plug-ins loaded with "-fplugin=name" are looked up in the plug-in
+    // directory. The headers for building plug-ins are not needed, and
+    // neither are the plug-ins of libcc1, which GDB loads into the compiler
+    // for its "compile" command (and which need libstdc++ and more).
+    if let Some(plugin_dir) = compiler_print_file_name(compiler_path, "plugin") {
+        if let Ok(entries) = std::fs::read_dir(&plugin_dir) {
+            const LIBCC1_PLUGINS: &[&str] = &["libcc1plugin.so", "libcp1plugin.so"];
+            let mut plugins: Vec<PathBuf> = entries
+                .filter_map(|entry| Some(entry.ok()?.path()))
+                .filter(|path| path.is_file() && path.extension() == Some("so".as_ref()))
+                .filter(|path| {
+                    !LIBCC1_PLUGINS
+                        .iter()
+                        .any(|name| path.file_name() == Some(name.as_ref()))
+                })
+                .collect();
+            plugins.sort();
+            info!("GCC plug-ins: {:?}", plugins);
+            path_list.extend(plugins);
         }
     }
 
-    Some(path_list)
+    // Tools which the driver runs when linking, for -flto, or -gsplit-dwarf.
+    let mut tools = vec!["ld", "collect2"];
+    if *version >= Version::new(4, 5, 0) {
+        tools.extend(&["lto-wrapper", "lto1"]);
+    }
+    if *version >= Version::new(4, 8, 0) {
+        tools.push("objcopy");
+    }
+    path_list.extend(compiler_driver_tools(compiler_path, &tools));
+
+    Ok(path_list)
 }
 
 #[inline]
-fn compiler_binaries_clang(_compiler_path: &Path) -> Option<Vec<PathBuf>> {
-    None
+fn compiler_binaries_clang(compiler_path: &Path, version: &Version) -> Result<Vec<PathBuf>> {
+    let mut path_list = Vec::new();
+
+    // The assembler is not needed at all when the integrated one is used.
+    if clang_uses_integrated_as(compiler_path) {
+        info!("Clang uses the integrated assembler");
+    } else {
+        path_list.extend(compiler_assembler(compiler_path)?);
+    }
+
+    // Before Clang 8, -gsplit-dwarf needed objcopy to extract the .dwo files.
+    let mut tools = vec!["ld"];
+    if *version < Version::new(8, 0, 0) {
+        tools.push("objcopy");
+    }
+    path_list.extend(compiler_driver_tools(compiler_path, &tools));
+
+    Ok(path_list)
 }
 
-fn compiler_fixup_tar<W: Write>(
-    compiler_kind: util::CompilerKind,
-    tar: &mut tar::Builder<W>,
-) -> Result<()> {
+/// Files read by the compiler driver which change its default options. They
+/// are packaged as-is, without looking for dependencies.
+fn compiler_config_files(compiler_kind: CompilerKind, compiler_path: &Path) -> Vec<PathBuf> {
     match compiler_kind {
-        util::CompilerKind::Gcc => compiler_fixup_tar_gcc(tar),
-        util::CompilerKind::Clang => compiler_fixup_tar_clang(tar),
+        CompilerKind::Gcc => compiler::gcc_config_files(compiler_path),
+        CompilerKind::Clang => compiler::clang_config_files(compiler_path),
+        CompilerKind::Rustc => Vec::new(),
     }
 }
 
-#[inline]
-fn compiler_fixup_tar_gcc<W: Write>(_tar: &mut tar::Builder<W>) -> Result<()> {
+fn compiler_binaries_rustc(toolchain: &Toolchain) -> Result<Vec<PathBuf>> {
+    let sysroot = match toolchain.sysroot {
+        Some(ref sysroot) => sysroot,
+        None => bail!(ErrorKind::CompilerInfoError(
+            "cannot obtain the Rust sysroot"
+        )),
+    };
+    let mut path_list = rustc::driver_libraries(sysroot);
+    if let Some(ref host) = toolchain.target {
+        path_list.extend(rustc::host_tools(sysroot, host));
+    }
+    Ok(path_list)
+}
+
+/// Files which the linker reads when linking programs: startup files,
+/// libgcc, and the libraries linked by default and by the Rust standard
+/// library. Those which are not installed are skipped.
+const LINKER_INPUTS: &[&str] = &[
+    "crt1.o",
+    "Scrt1.o",
+    "crti.o",
+    "crtn.o",
+    "crtbegin.o",
+    "crtend.o",
+    "crtbeginS.o",
+    "crtendS.o",
+    "libgcc.a",
+    "libgcc_eh.a",
+    "libgcc_s.so",
+    "libgcc_s.so.1",
+    "libc.so",
+    "libm.so",
+    "libdl.so",
+    "libdl.a",
+    "libpthread.so",
+    "libpthread.a",
+    "librt.so",
+    "librt.a",
+    "libutil.so",
+    "libutil.a",
+];
+
+/// Adds the linker used by rustc, which is usually a C compiler driver, and
+/// everything it needs to link programs: the programs and the LTO plug-in
+/// it runs, and the files listed in `LINKER_INPUTS`.
+fn add_rust_linker(solver: &mut bindep::Solver, linker: &str) -> Result<()> {
+    let linker = util::find_program(linker, None)
+        .chain_err(|| format!("cannot find linker {:?}", linker))?;
+    info!("Linker executable: {:?}", linker);
+    solver.scan_file(&linker)?;
+    for binary in compiler_driver_tools(&linker, &["ld", "collect2", "lto-wrapper"]) {
+        solver.scan_file(&binary)?;
+    }
+    if let Some(lto_plugin) = compiler_print_file_name(&linker, "liblto_plugin.so") {
+        solver.scan_file(&lto_plugin)?;
+    }
+
+    for name in LINKER_INPUTS {
+        let path = match compiler_print_file_name(&linker, name) {
+            Some(path) if path.is_file() => path,
+            _ => continue,
+        };
+        debug!("linker input {}: {:?}", name, path);
+        if !name.contains(".so") {
+            solver.add_file(&path);
+            continue;
+        }
+        // Development links to shared libraries are often linker scripts,
+        // like "GROUP ( /lib/libc.so.6 /usr/lib/libc_nonshared.a )".
+        let head = util::read_head(&path, 4).chain_err(|| format!("cannot read {:?}", path))?;
+        if head == b"\x7fELF" {
+            solver.scan_file(&path)?;
+            continue;
+        }
+        solver.add_file(&path);
+        for input in linker_script_inputs(&path) {
+            if input.extension() == Some("a".as_ref()) {
+                solver.add_file(&input);
+            } else {
+                solver.scan_file(&input)?;
+            }
+        }
+    }
     Ok(())
 }
 
+/// Files named with absolute paths in a linker script.
+fn linker_script_inputs(path: &Path) -> Vec<PathBuf> {
+    let script = match std::fs::read_to_string(path) {
+        Ok(script) => script,
+        Err(e) => {
+            warn!("cannot read linker script {:?}: {}", path, e);
+            return Vec::new();
+        }
+    };
+    script
+        .split(|c: char| c.is_whitespace() || c == '(' || c == ')')
+        .filter(|word| word.starts_with('/'))
+        .map(PathBuf::from)
+        .filter(|path| path.is_file())
+        .collect()
+}
+
+fn compiler_fixup(compiler_kind: CompilerKind, solver: &mut bindep::Solver) {
+    match compiler_kind {
+        CompilerKind::Gcc => compiler_fixup_gcc(solver),
+        CompilerKind::Clang => compiler_fixup_clang(solver),
+        CompilerKind::Rustc => {}
+    }
+}
+
+#[inline]
+fn compiler_fixup_gcc(_solver: &mut bindep::Solver) {}
+
 #[inline]
-fn compiler_fixup_tar_clang<W: Write>(tar: &mut tar::Builder<W>) -> Result<()> {
-    // There's always (?) C++ support.
-    tar.symlink("clang", "bin/clang++")?;
+fn compiler_fixup_clang(solver: &mut bindep::Solver) {
+    // There's always (?) C++ support, unless the C++ driver itself is the
+    // compiler being packaged.
+    if !solver.contains("bin/clang++") {
+        solver.symlink("clang", "bin/clang++");
+    }
 
     // Clang 4.x insists in reading /proc/cpuinfo, but it's used only at link
     // time. Provide the file preventively to silence the storm of warnings.
-    tar.empty("proc/cpuinfo")?;
+    solver.empty("proc/cpuinfo");
+}
+
+struct Toolchain {
+    kind: CompilerKind,
+    name: String,
+    version: Version,
+    /// Target triplet, e.g. "x86_64-linux-gnu".
+    target: Option<String>,
+    sysroot: Option<PathBuf>,
+    compiler_path: PathBuf,
+    /// Compiler as found in the PATH, which for rustc may be a proxy of
+    /// rustup instead of `compiler_path`.
+    invoked_path: PathBuf,
+    binaries: Vec<PathBuf>,
+}
+
+fn find_toolchain(compiler: &str) -> Result<Toolchain> {
+    let ccache_path = match util::find_program("ccache", None) {
+        Ok(path) => {
+            info!("ccache found at {:?}", path);
+            Some(path)
+        }
+        Err(e) => {
+            info!("ccache not found: {}", e);
+            None
+        }
+    };
+
+    let compiler_path = util::find_program(compiler, ccache_path.as_ref())?;
+    info!("Compiler executable: {:?}", compiler_path);
+
+    let compiler::CompilerInfo {
+        kind,
+        vendor,
+        version,
+        target,
+        default_std,
+    } = compiler::compiler_info(compiler_path.as_os_str())?;
+    match vendor {
+        Some(vendor) => info!(
+            "Detected compiler: {} ({}), version: {}",
+            kind.name(),
+            vendor,
+            version
+        ),
+        None => info!("Detected compiler: {}, version: {}", kind.name(), version),
+    }
+    if let Some(ref target) = target {
+        info!("Compiler target: {}", target);
+    }
+    if let Some(default_std) = default_std {
+        info!("Default C standard: {}", default_std);
+    }
+
+    let invoked_path = compiler_path.clone();
+    let mut compiler_path = compiler_path;
+    let sysroot = match kind {
+        CompilerKind::Rustc => {
+            let sysroot = rustc::sysroot(&compiler_path)?;
+            // Package the actual compiler instead of e.g. a rustup proxy.
+            if let Some(rustc) = rustc::binary(&sysroot) {
+                compiler_path = rustc;
+            }
+            Some(sysroot)
+        }
+        _ => sysroot::detect(&compiler_path)?,
+    };
+    if let Some(ref sysroot) = sysroot {
+        info!("Compiler sysroot: {:?}", sysroot);
+    }
+
+    let true_path =
+        util::find_program("true", None).chain_err(|| "cannot find \"true\" executable")?;
+
+    Ok(Toolchain {
+        kind,
+        name: kind.name().to_string(),
+        version,
+        target,
+        sysroot,
+        binaries: vec![compiler_path.clone(), true_path],
+        compiler_path,
+        invoked_path,
+    })
+}
+
+/// Options which affect which entries are packaged, and where.
+struct SolveOptions {
+    layout: layout::Layout,
+    sysroot: sysroot::Mode,
+    strip: bool,
+    library_path: Vec<PathBuf>,
+    missing_libs: bindep::MissingLibs,
+    hwcaps: hwcaps::Hwcaps,
+    languages: Option<Languages>,
+    rust_targets: Vec<String>,
+    rust_linker: String,
+}
+
+fn solve(toolchain: &Toolchain, options: &SolveOptions) -> Result<bindep::Solver> {
+    let mut solver = bindep::Solver::with_layout(options.layout);
+    solver.set_strip(options.strip);
+    solver.set_library_path(options.library_path.iter().cloned());
+    solver.set_hwcaps(options.hwcaps.subdirs());
+
+    for binary in &toolchain.binaries {
+        solver.scan_file(binary)?;
+    }
+    let binaries = compiler_binaries(toolchain, options)?;
+    for binary in binaries {
+        solver.scan_file(binary.as_path())?;
+    }
+    for path in compiler_config_files(toolchain.kind, &toolchain.compiler_path) {
+        solver.add_file(&path);
+    }
 
+    compiler_fixup(toolchain.kind, &mut solver);
+    match (toolchain.kind, &toolchain.sysroot) {
+        (CompilerKind::Rustc, Some(sysroot)) => {
+            let targets = match options.rust_targets {
+                ref targets if !targets.is_empty() => targets.clone(),
+                _ => toolchain.target.iter().cloned().collect(),
+            };
+            rustc::add_targets(&mut solver, sysroot, &targets)?;
+            add_rust_linker(&mut solver, &options.rust_linker)?;
+        }
+        (_, Some(sysroot)) => sysroot::add(&mut solver, sysroot, options.sysroot)?,
+        (_, None) => {}
+    }
+    let collisions = solver.collisions();
+    if !collisions.is_empty() {
+        for collision in collisions {
+            warn!("{}", collision);
+        }
+        bail!(
+            "{} files conflict with others in the tarball layout (first: {})",
+            collisions.len(),
+            collisions[0]
+        );
+    }
+    let missing = solver.missing();
+    if !missing.is_empty() {
+        let names: Vec<String> = missing.iter().map(ToString::to_string).collect();
+        match options.missing_libs {
+            bindep::MissingLibs::Error => bail!(
+                "{} needed libraries cannot be found: {}",
+                missing.len(),
+                names.join(", ")
+            ),
+            bindep::MissingLibs::Warn => warn!(
+                "{} needed libraries cannot be found: {}",
+                missing.len(),
+                names.join(", ")
+            ),
+            bindep::MissingLibs::Ignore => debug!("missing libraries: {}", names.join(", ")),
+        }
+    }
+    solver.data(
+        manifest::COMPILER_INFO_PATH,
+        format!("{} {}\n", toolchain.name, toolchain.version),
+    );
+    Ok(solver)
+}
+
+fn write_tarball<W: Write>(writer: W, solver: &bindep::Solver) -> Result<W> {
+    let mut tar = tar::Builder::new(writer);
+    let checksums = solver.write(&mut tar)?;
+    tar.data(
+        manifest::CHECKSUMS_PATH,
+        manifest::format_checksums(&checksums).as_bytes(),
+    )?;
+    Ok(tar.into_inner()?)
+}
+
+fn gzip_encoder<W: Write>(writer: W) -> Result<gzip::Encoder<W>> {
+    // Leave the modification time unset, for reproducible output.
+    let header = gzip::HeaderBuilder::new().modification_time(0).finish();
+    Ok(gzip::Encoder::with_options(
+        writer,
+        gzip::EncodeOptions::new().header(header),
+    )?)
+}
+
+fn open_output(path: &Path) -> Result<Box<dyn Write>> {
+    if path == Path::new("-") {
+        Ok(Box::new(std::io::stdout()))
+    } else {
+        let file = std::fs::File::create(path)
+            .chain_err(|| format!("cannot open {:?} for writing", path))?;
+        Ok(Box::new(std::io::BufWriter::new(file)))
+    }
+}
+
+fn write_hash_named(
+    output_dir: &Path,
+    algorithm: csum::Algorithm,
+    solver: &bindep::Solver,
+) -> Result<PathBuf> {
+    let temp_path = output_dir.join(format!(".popsicle-{}.tar.gz", std::process::id()));
+    let file = std::fs::File::create(&temp_path)
+        .chain_err(|| format!("cannot open {:?} for writing", temp_path))?;
+    let writer = csum::CSumWriter::with_algorithm(std::io::BufWriter::new(file), algorithm);
+    let encoder = write_tarball(gzip_encoder(writer)?, solver)?;
+    let (mut writer, checksum) = encoder.finish().into_result()?.into_inner();
+    writer.flush()?;
+
+    let path = output_dir.join(format!("{}.tar.gz", checksum));
+    std::fs::rename(&temp_path, &path)
+        .chain_err(|| format!("cannot rename {:?} to {:?}", temp_path, path))?;
+    Ok(path)
+}
+
+fn copy_output(targz_path: &Path, output_path: &Path) -> Result<()> {
+    if output_path != Path::new("-") {
+        // Try to avoid copying data around if possible.
+        if output_path.is_file() {
+            std::fs::remove_file(output_path)?;
+        }
+        match std::fs::hard_link(targz_path, output_path) {
+            Ok(_) => return Ok(()),
+            Err(e) => debug!("cannot hardlink {:?}: {}, copying", output_path, e),
+        }
+    }
+    let mut output = open_output(output_path)?;
+    std::io::copy(
+        &mut std::io::BufReader::new(std::fs::File::open(targz_path)?),
+        &mut output,
+    )
+    .chain_err(|| format!("cannot copy {:?} into {:?}", targz_path, output_path))?;
+    output.flush()?;
+    Ok(())
+}
+
+fn load_manifest(spec: &str, options: &SolveOptions) -> Result<manifest::Manifest> {
+    let path = Path::new(spec);
+    let is_tarball = spec.ends_with(".tar.gz") || spec.ends_with(".tgz");
+    if is_tarball && path.is_file() {
+        manifest::Manifest::from_tarball(path)
+    } else {
+        manifest::Manifest::from_solver(&solve(&find_toolchain(spec)?, options)?)
+    }
+}
+
+/// Prints the differences between two toolchains, returning the exit
+/// status: like diff(1), non-zero when differences are found.
+fn run_diff(old: &str, new: &str, options: &SolveOptions) -> Result<i32> {
+    let diff = load_manifest(old, options)?.diff(&load_manifest(new, options)?);
+    print!("{}", diff);
+    Ok(if diff.is_empty() { 0 } else { 1 })
+}
+
+fn run_graph(compiler: &str, format: graph::Format, options: &SolveOptions) -> Result<()> {
+    let solver = solve(&find_toolchain(compiler)?, options)?;
+    let stdout = std::io::stdout();
+    graph::Graph::from_solver(&solver).write(format, &mut stdout.lock())?;
     Ok(())
 }
 
+fn run_verify(tarball: &Path, integrity: bool) -> Result<()> {
+    // Reading the whole archive already checks its structure.
+    let manifest = manifest::Manifest::from_tarball(tarball)?;
+    if integrity {
+        let problems = manifest.verify()?;
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        if !problems.is_empty() {
+            bail!(
+                "{} integrity problems found in {:?}",
+                problems.len(),
+                tarball
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(StructOpt)]
+enum Command {
+    #[structopt(
+        name = "diff",
+        about = "Compares two toolchain environments, given as tarballs or compiler names"
+    )]
+    Diff {
+        #[structopt(help = "Toolchain tarball or compiler name")]
+        old: String,
+        #[structopt(help = "Toolchain tarball or compiler name")]
+        new: String,
+    },
+
+    #[structopt(
+        name = "graph",
+        about = "Prints the dependency graph of the files which would be packaged"
+    )]
+    Graph {
+        #[structopt(
+            long = "format",
+            default_value = "tree",
+            raw(possible_values = "graph::Format::NAMES"),
+            help = "Output format"
+        )]
+        format: graph::Format,
+        #[structopt(help = "Specify the name of the compiler")]
+        compiler: String,
+    },
+
+    #[structopt(name = "verify", about = "Checks that a toolchain tarball can be read")]
+    Verify {
+        #[structopt(
+            long = "integrity",
+            help = "Check the contents of each file against the embedded checksums"
+        )]
+        integrity: bool,
+        #[structopt(parse(from_os_str), help = "Toolchain tarball")]
+        tarball: PathBuf,
+    },
+}
+
 #[derive(StructOpt)]
-#[structopt(name = "popsicle", about = "Creates toolchain tarballs for Icecream")]
+#[structopt(
+    name = "popsicle",
+    about = "Creates toolchain tarballs for Icecream and sccache-dist",
+    raw(setting = "structopt::clap::AppSettings::ArgsNegateSubcommands")
+)]
 struct CliOptions {
     #[structopt(
         short = "f",
@@ -137,48 +790,258 @@ struct CliOptions {
     )]
     force_rebuild: bool,
 
+    #[structopt(
+        short = "o",
+        long = "output",
+        parse(from_os_str),
+        help = "Write the toolchain tarball to a path (use \"-\" for standard output)"
+    )]
+    output: Option<PathBuf>,
+
+    #[structopt(
+        long = "no-cache",
+        raw(requires = r#""output""#),
+        help = "Build the toolchain tarball without using the cache"
+    )]
+    no_cache: bool,
+
+    #[structopt(
+        long = "hash-name",
+        raw(possible_values = "csum::Algorithm::NAMES"),
+        help = "Name the tarball after the digest of its contents, as icecc-create-env does \
+                with \"md5\" (when used, --output specifies a directory)"
+    )]
+    hash_name: Option<csum::Algorithm>,
+
+    #[structopt(
+        long = "sccache",
+        help = "Write the tarball for sccache-dist, named after its digest (blake2b unless \
+                --hash-name is given), and print the \"dist.toolchains\" configuration entry"
+    )]
+    sccache: bool,
+
+    #[structopt(
+        long = "sysroot",
+        default_value = "none",
+        raw(possible_values = "sysroot::Mode::NAMES"),
+        help = "Parts of the compiler sysroot to package, when it is not the host root: \
+                \"compile\" for headers and startup files, \"full\" for everything"
+    )]
+    sysroot: sysroot::Mode,
+
+    #[structopt(
+        long = "layout",
+        default_value = "merged",
+        raw(possible_values = "layout::Layout::NAMES"),
+        help = "Arrangement of files in the tarball: \"merged\" folds /usr and /sbin into / \
+                and /bin, \"split\" keeps host paths (for split-usr hosts)"
+    )]
+    layout: layout::Layout,
+
+    #[structopt(
+        long = "strip",
+        help = "Remove debug information and symbol tables from the packaged ELF files"
+    )]
+    strip: bool,
+
+    #[structopt(
+        long = "library-path",
+        parse(from_os_str),
+        raw(number_of_values = "1"),
+        help = "Search a directory for shared libraries before the system ones, like \
+                LD_LIBRARY_PATH (can be given several times, or as a colon-separated list)"
+    )]
+    library_path: Vec<PathBuf>,
+
+    #[structopt(
+        long = "ld-library-path",
+        help = "Search the directories in $LD_LIBRARY_PATH for shared libraries, after the \
+                ones given with --library-path"
+    )]
+    ld_library_path: bool,
+
+    #[structopt(
+        long = "languages",
+        help = "Comma-separated list of languages to package the GCC front-ends for, or \"all\" \
+                (default: \"c,c++\", when installed)"
+    )]
+    languages: Option<Languages>,
+
+    #[structopt(
+        long = "rust-target",
+        raw(number_of_values = "1", use_delimiter = "true"),
+        help = "Target to package the Rust standard library for (can be given several times, \
+                default: the host)"
+    )]
+    rust_targets: Vec<String>,
+
+    #[structopt(
+        long = "rust-linker",
+        default_value = "cc",
+        help = "Linker program used by rustc"
+    )]
+    rust_linker: String,
+
+    #[structopt(
+        long = "hwcaps",
+        default_value = "host",
+        raw(possible_values = "hwcaps::Hwcaps::NAMES"),
+        help = "Optimized library variants to package: \"host\" for those used by the host, \
+                \"none\" for baseline libraries only, or the highest glibc-hwcaps level \
+                supported by all the nodes"
+    )]
+    hwcaps: hwcaps::Hwcaps,
+
+    #[structopt(
+        long = "missing-libs",
+        default_value = "warn",
+        raw(possible_values = "bindep::MissingLibs::NAMES"),
+        help = "What to do when needed shared libraries cannot be found: \"error\" fails, \
+                \"warn\" lists them and continues, \"ignore\" does nothing"
+    )]
+    missing_libs: bindep::MissingLibs,
+
+    #[structopt(
+        short = "n",
+        long = "dry-run",
+        raw(conflicts_with_all = r#"&["output", "force_rebuild"]"#),
+        help = "Print the files which would be packaged, without writing anything"
+    )]
+    dry_run: bool,
+
     #[structopt(help = "Specify the name of the compiler to package")]
-    compiler: String,
+    compiler: Option<String>,
+
+    #[structopt(subcommand)]
+    command: Option<Command>,
 }
 
-fn run() -> Result<()> {
-    env_logger::init();
+/// Directories from --library-path, followed by those in $LD_LIBRARY_PATH
+/// when requested. Empty entries, which the dynamic loader takes as the
+/// current directory, and directories which do not exist are skipped.
+fn library_path(options: &CliOptions) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = options
+        .library_path
+        .iter()
+        .flat_map(std::env::split_paths)
+        .collect();
+    if options.ld_library_path {
+        if let Some(value) = std::env::var_os("LD_LIBRARY_PATH") {
+            dirs.extend(std::env::split_paths(&value));
+        }
+    }
+    let dirs = dirs
+        .into_iter()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .filter_map(|dir| match dir.canonicalize() {
+            Ok(dir) => Some(dir),
+            Err(e) => {
+                warn!("ignoring library path entry {:?}: {}", dir, e);
+                None
+            }
+        })
+        .collect();
+    debug!("library path: {:?}", dirs);
+    dirs
+}
 
-    let options = CliOptions::from_args();
+fn run() -> Result<i32> {
+    // Warnings are shown unless $RUST_LOG says otherwise.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("warn")).init();
 
-    let ccache_path = match util::find_program("ccache", None) {
-        Ok(path) => {
-            info!("ccache found at {:?}", path);
-            Some(path)
-        }
-        Err(e) => {
-            warn!("error finding ccache: {}", e);
-            None
-        }
+    let options = CliOptions::from_args();
+    let solve_options = SolveOptions {
+        layout: options.layout,
+        sysroot: options.sysroot,
+        strip: options.strip,
+        library_path: library_path(&options),
+        missing_libs: options.missing_libs,
+        hwcaps: options.hwcaps,
+        languages: options.languages.clone(),
+        rust_targets: options.rust_targets.clone(),
+        rust_linker: options.rust_linker.clone(),
     };
 
-    let compiler_path = util::find_program(options.compiler, ccache_path.as_ref())?;
-    info!("Compiler executable: {:?}", compiler_path);
+    if let Some(command) = options.command {
+        return match command {
+            Command::Diff { old, new } => run_diff(&old, &new, &solve_options),
+            Command::Verify { tarball, integrity } => run_verify(&tarball, integrity).map(|_| 0),
+            Command::Graph { compiler, format } => {
+                run_graph(&compiler, format, &solve_options).map(|_| 0)
+            }
+        };
+    }
 
-    let (kind, name, version) = util::compiler_info(compiler_path.as_os_str())?;
-    info!("Detected compiler: {}, version: {}", name, version);
+    let toolchain = match options.compiler {
+        Some(ref compiler) => find_toolchain(compiler)?,
+        None => structopt::clap::Error::with_description(
+            "The name of the compiler to package was not provided",
+            structopt::clap::ErrorKind::MissingRequiredArgument,
+        )
+        .exit(),
+    };
+    let (name, version) = (&toolchain.name, toolchain.version.to_string());
+    let solver = solve(&toolchain, &solve_options)?;
 
-    let mut assembler_path = compiler_path.clone();
-    assembler_path.set_file_name("as");
-    if !assembler_path.is_file() {
-        assembler_path =
-            util::find_program("as", None).chain_err(|| "cannot find assembler executable")?;
+    if options.dry_run {
+        for entry in solver.entries() {
+            println!("{}", entry);
+        }
+        return Ok(0);
     }
-    info!("Assembler executable: {:?}", assembler_path);
 
-    let true_path =
-        util::find_program("true", None).chain_err(|| "cannot find \"true\" executable")?;
+    let hash_name = match options.hash_name {
+        None if options.sccache => Some(csum::Algorithm::Blake2b),
+        hash_name => hash_name,
+    };
+    if hash_name.is_some() && options.output == Some(PathBuf::from("-")) {
+        bail!("--hash-name and --sccache cannot be used when writing to standard output");
+    }
+    let report = |path: &Path| -> Result<()> {
+        if options.sccache {
+            let archive = path
+                .canonicalize()
+                .chain_err(|| format!("cannot resolve path {:?}", path))?;
+            print!(
+                "{}",
+                sccache::toolchain_config(
+                    &toolchain.invoked_path,
+                    &archive,
+                    &sccache::archive_compiler_path(options.layout, &toolchain.compiler_path),
+                )
+            );
+        } else if path != Path::new("-") {
+            println!("{}", path.to_str().unwrap());
+        }
+        Ok(())
+    };
+
+    if options.no_cache {
+        let output_path = options.output.as_ref().unwrap();
+        let output_path = match hash_name {
+            Some(algorithm) => write_hash_named(output_path, algorithm, &solver)?,
+            None => {
+                // Stream the compressed tarball directly, without temporary files.
+                let encoder = gzip_encoder(open_output(output_path)?)?;
+                let encoder = write_tarball(encoder, &solver)?;
+                encoder.finish().into_result()?.flush()?;
+                output_path.clone()
+            }
+        };
+        report(&output_path)?;
+        return Ok(0);
+    }
 
     let mut cache = cache::Cache::new(name.as_str()).chain_err(|| "Could not open cache")?;
     info!("cache: {:?}", cache);
 
-    let old_version = cache.get("compiler-version")?;
-    cache.add("compiler-version", version.as_bytes())?;
+    // Stripped and unstripped tarballs are cached separately.
+    let variant = if options.strip { "-stripped" } else { "" };
+    let key = |key: &str| format!("{}{}", key, variant);
+    let targz_name = |version: &str| format!("{}-{}{}.tar.gz", name, version, variant);
+
+    let old_version = cache.get(key("compiler-version"))?;
+    cache.add(key("compiler-version"), version.as_bytes())?;
 
     // The tar file is temporary, and therefore removed immediately.
     let tar_path = cache.path_for("tar-file")?;
@@ -192,37 +1055,27 @@ fn run() -> Result<()> {
     std::fs::remove_file(&tar_path)?;
 
     let writer = csum::CSumWriter::new(std::io::BufWriter::new(tar_file));
-    let mut solver = bindep::Solver::new(writer)
-        .chain_err(|| format!("cannot create write buffer for {:?}", tar_path))?;
-
-    for binary in &[&compiler_path, &assembler_path, &true_path] {
-        solver.scan_file(binary.as_path())?;
-    }
-    if let Some(binaries) = compiler_binaries(kind, compiler_path) {
-        for binary in binaries {
-            solver.scan_file(binary.as_path())?;
-        }
-    }
-
-    let mut tar = solver.into_inner();
-    compiler_fixup_tar(kind, &mut tar)?;
+    let writer = write_tarball(writer, &solver)
+        .chain_err(|| format!("cannot write tarball data to {:?}", tar_path))?;
 
     let (mut tar_file, checksum) = {
-        let (writer, checksum) = tar.into_inner()?.into_inner();
+        let (writer, checksum) = writer.into_inner();
         (writer.into_inner().unwrap(), checksum)
     };
     assert_eq!(0, tar_file.seek(std::io::SeekFrom::Start(0))?);
 
-    cache.add("checksum", checksum)?;
+    cache.add(key("checksum"), checksum)?;
     debug!("cache valid={}", cache.is_valid());
 
-    let targz_path = cache.path_for(&format!("{}-{}.tar.gz", name, version))?;
+    let targz_path = cache.path_for(targz_name(&version))?;
     if options.force_rebuild || !(targz_path.is_file() && cache.is_valid()) {
-        if let Some(version) = old_version {
-            cache.del(format!("{}-{}.tar.gz", name, version))?;
+        if let Some(old_version) = old_version {
+            cache.del(targz_name(&old_version))?;
         }
+        // Unlink instead of truncating, the file may be hardlinked elsewhere.
+        cache.del(targz_name(&version))?;
         let mut encoder =
-            gzip::Encoder::new(std::io::BufWriter::new(std::fs::File::create(&targz_path)?))?;
+            gzip_encoder(std::io::BufWriter::new(std::fs::File::create(&targz_path)?))?;
         info!("compressing tarball...");
         std::io::copy(&mut std::io::BufReader::new(tar_file), &mut encoder).chain_err(|| {
             format!(
@@ -233,6 +1086,36 @@ fn run() -> Result<()> {
         encoder.finish().into_result()?;
     }
 
-    println!("{}", targz_path.to_str().unwrap());
-    Ok(())
+    let targz_path = match hash_name {
+        Some(algorithm) => {
+            let checksum = algorithm
+                .digest_reader(&mut std::fs::File::open(&targz_path)?)
+                .chain_err(|| format!("cannot calculate digest of {:?}", targz_path))?;
+            let file_name = format!("{}.tar.gz", checksum);
+            let hash_path = match options.output {
+                Some(ref output_dir) => output_dir.join(&file_name),
+                None => {
+                    if let Some(old_file_name) = cache.get(key("hash-file"))? {
+                        if old_file_name != file_name {
+                            cache.del(old_file_name)?;
+                        }
+                    }
+                    cache.add(key("hash-file"), file_name.as_bytes())?;
+                    cache.path_for(&file_name)?
+                }
+            };
+            copy_output(&targz_path, &hash_path)?;
+            hash_path
+        }
+        None => match options.output {
+            Some(ref output_path) => {
+                copy_output(&targz_path, output_path)?;
+                output_path.clone()
+            }
+            None => targz_path,
+        },
+    };
+
+    report(&targz_path)?;
+    Ok(0)
 }
@@ -0,0 +1,454 @@
+//
+// manifest.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use error_chain::bail;
+use libflate::gzip;
+use log::warn;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+
+use crate::bindep::{self, Entry, Solver};
+use crate::csum::Checksum;
+use crate::errors::*;
+
+/// Directory inside the tarball which contains files with metadata about it.
+pub const METADATA_DIR: &str = ".popsicle";
+
+/// Path inside the tarball of the file which records the compiler name and version.
+pub const COMPILER_INFO_PATH: &str = ".popsicle/compiler";
+
+/// Path inside the tarball of the file with the checksums of each entry, in
+/// the same format used by the "b2sum" tool.
+pub const CHECKSUMS_PATH: &str = ".popsicle/B2SUMS";
+
+pub fn format_checksums(checksums: &[(PathBuf, Checksum)]) -> String {
+    let mut text = String::new();
+    for (path, csum) in checksums {
+        text.push_str(&format!("{}  {}\n", csum, path.display()));
+    }
+    text
+}
+
+fn parse_checksums(data: &[u8]) -> BTreeMap<PathBuf, String> {
+    let mut checksums = BTreeMap::new();
+    for line in String::from_utf8_lossy(data).lines() {
+        match line.find(' ') {
+            Some(pos) if line.len() > pos + 2 => {
+                let path = &line[pos + 2..];
+                checksums.insert(PathBuf::from(path), line[..pos].to_string());
+            }
+            _ => warn!("malformed checksum line: {:?}", line),
+        }
+    }
+    checksums
+}
+
+fn is_metadata(path: &Path) -> bool {
+    path.starts_with(METADATA_DIR)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Item {
+    File {
+        csum: Checksum,
+        soname: Option<String>,
+    },
+    Symlink(PathBuf),
+    Other,
+}
+
+impl Item {
+    fn from_data(data: &[u8]) -> Self {
+        Item::File {
+            csum: Checksum::of(data),
+            soname: bindep::soname(data),
+        }
+    }
+}
+
+/// Describes the contents of a toolchain environment.
+#[derive(Debug, Default)]
+pub struct Manifest {
+    compiler: Option<String>,
+    checksums: Option<BTreeMap<PathBuf, String>>,
+    items: BTreeMap<PathBuf, Item>,
+}
+
+impl Manifest {
+    pub fn from_tarball(path: &Path) -> Result<Self> {
+        let file = File::open(path).chain_err(|| format!("cannot open {:?}", path))?;
+        let decoder = gzip::Decoder::new(BufReader::new(file))
+            .chain_err(|| format!("cannot decompress {:?}", path))?;
+        Self::from_tar(decoder).chain_err(|| format!("cannot read tarball {:?}", path))
+    }
+
+    pub fn from_tar<R: Read>(reader: R) -> Result<Self> {
+        let mut manifest = Self::default();
+        let mut archive = tar::Archive::new(reader);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            let item = match entry.header().entry_type() {
+                tar::EntryType::Regular => {
+                    // The size comes from a header which may be corrupted,
+                    // so do not trust it for more than a small buffer.
+                    let mut data = Vec::with_capacity(entry.size().min(1 << 20) as usize);
+                    entry.read_to_end(&mut data)?;
+                    manifest.check_metadata(&path, &data);
+                    Item::from_data(&data)
+                }
+                tar::EntryType::Symlink => match entry.link_name()? {
+                    Some(target) => Item::Symlink(target.into_owned()),
+                    None => Item::Other,
+                },
+                tar::EntryType::Link => match entry.link_name()? {
+                    Some(target) => manifest
+                        .items
+                        .get(target.as_ref())
+                        .cloned()
+                        .unwrap_or(Item::Other),
+                    None => Item::Other,
+                },
+                _ => Item::Other,
+            };
+            manifest.items.insert(path, item);
+        }
+        Ok(manifest)
+    }
+
+    pub fn from_solver(solver: &Solver) -> Result<Self> {
+        let mut manifest = Self::default();
+        for entry in solver.entries() {
+            let path = entry.tar_path().to_path_buf();
+            let item = match entry {
+                Entry::File { source, .. } => Item::from_data(&solver.contents(source)?),
+                Entry::Hardlink { target, .. } => manifest.items[target].clone(),
+                Entry::Symlink { target, .. } => Item::Symlink(target.clone()),
+                Entry::Data { data, .. } => {
+                    manifest.check_metadata(&path, data);
+                    Item::from_data(data)
+                }
+            };
+            manifest.items.insert(path, item);
+        }
+        Ok(manifest)
+    }
+
+    fn check_metadata(&mut self, path: &Path, data: &[u8]) {
+        if path == Path::new(COMPILER_INFO_PATH) {
+            self.compiler = Some(String::from_utf8_lossy(data).trim().to_string());
+        } else if path == Path::new(CHECKSUMS_PATH) {
+            self.checksums = Some(parse_checksums(data));
+        }
+    }
+
+    /// Checks the contents of each file against the recorded checksums.
+    pub fn verify(&self) -> Result<Vec<Problem>> {
+        let checksums = match self.checksums {
+            Some(ref checksums) => checksums,
+            None => bail!("no checksums found in {:?}", CHECKSUMS_PATH),
+        };
+
+        let mut problems = Vec::new();
+        for (path, item) in &self.items {
+            if let Item::File { ref csum, .. } = item {
+                match checksums.get(path) {
+                    Some(expected) if *expected == csum.to_string() => continue,
+                    Some(_) => problems.push(Problem::Mismatch(path.clone())),
+                    None if path == Path::new(CHECKSUMS_PATH) => continue,
+                    None => problems.push(Problem::Unlisted(path.clone())),
+                }
+            }
+        }
+        for path in checksums.keys() {
+            if !self.items.contains_key(path) {
+                problems.push(Problem::Missing(path.clone()));
+            }
+        }
+        Ok(problems)
+    }
+
+    fn sonames(&self) -> BTreeMap<&str, BTreeSet<&str>> {
+        let mut sonames = BTreeMap::new();
+        for item in self.items.values() {
+            if let Item::File {
+                soname: Some(soname),
+                ..
+            } = item
+            {
+                let base = match soname.find(".so") {
+                    Some(pos) => &soname[..pos + 3],
+                    None => soname.as_str(),
+                };
+                sonames
+                    .entry(base)
+                    .or_insert_with(BTreeSet::new)
+                    .insert(soname.as_str());
+            }
+        }
+        sonames
+    }
+
+    pub fn diff(&self, other: &Manifest) -> Diff {
+        let mut diff = Diff::default();
+
+        if self.compiler != other.compiler {
+            diff.compiler = Some((self.compiler.clone(), other.compiler.clone()));
+        }
+
+        for (path, item) in &self.items {
+            match other.items.get(path) {
+                _ if is_metadata(path) => continue,
+                Some(other_item) if other_item != item => diff.changed.push(path.clone()),
+                Some(_) => continue,
+                None => diff.removed.push(path.clone()),
+            }
+        }
+        for path in other.items.keys() {
+            if !is_metadata(path) && !self.items.contains_key(path) {
+                diff.added.push(path.clone());
+            }
+        }
+
+        let sonames = self.sonames();
+        let other_sonames = other.sonames();
+        for (base, names) in &sonames {
+            if let Some(other_names) = other_sonames.get(base) {
+                if names != other_names {
+                    diff.sonames.push((join(names), join(other_names)));
+                }
+            }
+        }
+
+        diff
+    }
+}
+
+fn join(names: &BTreeSet<&str>) -> String {
+    names.iter().cloned().collect::<Vec<_>>().join(", ")
+}
+
+/// Integrity problem found in a toolchain environment.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Problem {
+    Mismatch(PathBuf),
+    Missing(PathBuf),
+    Unlisted(PathBuf),
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Problem::Mismatch(path) => write!(f, "{}: checksum mismatch", path.display()),
+            Problem::Missing(path) => write!(f, "{}: missing", path.display()),
+            Problem::Unlisted(path) => write!(f, "{}: no checksum", path.display()),
+        }
+    }
+}
+
+/// Differences between two toolchain environments.
+#[derive(Debug, Default)]
+pub struct Diff {
+    pub compiler: Option<(Option<String>, Option<String>)>,
+    pub added: Vec<PathBuf>,
+    pub removed: Vec<PathBuf>,
+    pub changed: Vec<PathBuf>,
+    pub sonames: Vec<(String, String)>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.compiler.is_none()
+            && self.added.is_empty()
+            && self.removed.is_empty()
+            && self.changed.is_empty()
+            && self.sonames.is_empty()
+    }
+}
+
+impl fmt::Display for Diff {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some((ref old, ref new)) = self.compiler {
+            writeln!(
+                f,
+                "compiler: {} -> {}",
+                old.as_deref().unwrap_or("unknown"),
+                new.as_deref().unwrap_or("unknown")
+            )?;
+        }
+        for (old, new) in &self.sonames {
+            writeln!(f, "library: {} -> {}", old, new)?;
+        }
+        for path in &self.removed {
+            writeln!(f, "- {}", path.display())?;
+        }
+        for path in &self.added {
+            writeln!(f, "+ {}", path.display())?;
+        }
+        for path in &self.changed {
+            writeln!(f, "M {}", path.display())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn manifest(compiler: &str, items: &[(&str, &str)]) -> Manifest {
+        let mut manifest = Manifest {
+            compiler: Some(compiler.to_string()),
+            ..Default::default()
+        };
+        for (path, data) in items {
+            manifest
+                .items
+                .insert(PathBuf::from(path), Item::from_data(data.as_bytes()));
+        }
+        manifest
+    }
+
+    #[test]
+    fn diff_identical() {
+        let a = manifest("gcc 7.2.0", &[("bin/gcc", "gcc"), ("lib/libc.so.6", "c")]);
+        let b = manifest("gcc 7.2.0", &[("bin/gcc", "gcc"), ("lib/libc.so.6", "c")]);
+        assert!(a.diff(&b).is_empty());
+    }
+
+    #[test]
+    fn diff_files() {
+        let a = manifest("gcc 7.2.0", &[("bin/gcc", "gcc"), ("lib/libz.so.1", "z")]);
+        let b = manifest("gcc 7.3.0", &[("bin/gcc", "gcc 2"), ("lib/libm.so.6", "m")]);
+        let diff = a.diff(&b);
+        assert_eq!(
+            Some((Some("gcc 7.2.0".into()), Some("gcc 7.3.0".into()))),
+            diff.compiler
+        );
+        assert_eq!(vec![PathBuf::from("lib/libm.so.6")], diff.added);
+        assert_eq!(vec![PathBuf::from("lib/libz.so.1")], diff.removed);
+        assert_eq!(vec![PathBuf::from("bin/gcc")], diff.changed);
+    }
+
+    #[test]
+    fn diff_sonames_only() {
+        let library = |soname: &str| Item::File {
+            csum: Checksum::of("z"),
+            soname: Some(soname.to_string()),
+        };
+        let mut a = manifest("gcc 7.2.0", &[]);
+        let mut b = manifest("gcc 7.2.0", &[]);
+        let path = PathBuf::from("lib/libz.so");
+        a.items.insert(path.clone(), library("libz.so.1"));
+        b.items.insert(path, library("libz.so.2"));
+        let diff = a.diff(&b);
+        assert_eq!(
+            vec![("libz.so.1".to_string(), "libz.so.2".to_string())],
+            diff.sonames
+        );
+        assert!(!diff.is_empty());
+    }
+
+    #[test]
+    fn tarball_roundtrip() {
+        let mut solver = Solver::new();
+        solver.data(COMPILER_INFO_PATH, "gcc 7.2.0\n");
+        solver.empty("proc/cpuinfo");
+        let data = write_tar(&solver, |_| {});
+
+        let from_tar = Manifest::from_tar(data.as_slice()).unwrap();
+        let from_solver = Manifest::from_solver(&solver).unwrap();
+        assert_eq!(Some("gcc 7.2.0"), from_tar.compiler.as_deref());
+        assert!(from_solver.diff(&from_tar).is_empty());
+        assert_eq!(Vec::<Problem>::new(), from_tar.verify().unwrap());
+    }
+
+    #[test]
+    fn hardlinks_roundtrip() {
+        let dir = tempdir::TempDir::new("manifest").unwrap();
+        let (gcc, gcc_12) = (dir.path().join("gcc"), dir.path().join("gcc-12"));
+        std::fs::write(&gcc, "compiler").unwrap();
+        std::fs::write(&gcc_12, "compiler").unwrap();
+
+        let mut solver = Solver::new();
+        solver.add_file(&gcc);
+        solver.add_file(&gcc_12);
+        match solver.entries().last() {
+            Some(Entry::Hardlink { target, .. }) => assert_eq!(&layout_path(&gcc), target),
+            other => panic!("unexpected entry: {:?}", other),
+        }
+        let data = write_tar(&solver, |_| {});
+
+        let from_tar = Manifest::from_tar(data.as_slice()).unwrap();
+        assert_eq!(
+            from_tar.items[&layout_path(&gcc)],
+            from_tar.items[&layout_path(&gcc_12)]
+        );
+        assert!(Manifest::from_solver(&solver)
+            .unwrap()
+            .diff(&from_tar)
+            .is_empty());
+        assert_eq!(Vec::<Problem>::new(), from_tar.verify().unwrap());
+    }
+
+    fn layout_path(path: &Path) -> PathBuf {
+        crate::layout::Layout::Merged.place(path)
+    }
+
+    fn write_tar<F>(solver: &Solver, tamper: F) -> Vec<u8>
+    where
+        F: FnOnce(&mut Vec<(PathBuf, Checksum)>),
+    {
+        use crate::bindep::TarBuilderExt;
+
+        let mut tar = tar::Builder::new(Vec::new());
+        let mut checksums = solver.write(&mut tar).unwrap();
+        tamper(&mut checksums);
+        tar.data(CHECKSUMS_PATH, format_checksums(&checksums).as_bytes())
+            .unwrap();
+        tar.into_inner().unwrap()
+    }
+
+    #[test]
+    fn verify_detects_problems() {
+        let mut solver = Solver::new();
+        solver.data("etc/one", "one");
+        solver.data("etc/two", "two");
+        solver.data("etc/three", "three");
+        let data = write_tar(&solver, |checksums| {
+            checksums.retain(|(path, _)| path != Path::new("etc/one"));
+            checksums[0].1 = Checksum::of("tampered");
+            checksums.push(("etc/four".into(), Checksum::of("four")));
+        });
+
+        let problems = Manifest::from_tar(data.as_slice())
+            .unwrap()
+            .verify()
+            .unwrap();
+        assert_eq!(
+            vec![
+                Problem::Unlisted("etc/one".into()),
+                Problem::Mismatch("etc/two".into()),
+                Problem::Missing("etc/four".into()),
+            ],
+            problems
+        );
+    }
+
+    #[test]
+    fn verify_without_checksums() {
+        let mut tar = tar::Builder::new(Vec::new());
+        Solver::new().write(&mut tar).unwrap();
+        let data = tar.into_inner().unwrap();
+        assert!(Manifest::from_tar(data.as_slice())
+            .unwrap()
+            .verify()
+            .is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//
+// rustc.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use error_chain::bail;
+use log::info;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use crate::bindep::Solver;
+use crate::errors::*;
+
+/// Asks rustc for its sysroot, where the compiler libraries and the
+/// standard library for each target are installed.
+pub fn sysroot(compiler_path: &Path) -> Result<PathBuf> {
+    let output = Command::new(compiler_path)
+        .args(["--print", "sysroot"])
+        .env("LC_ALL", "C")
+        .output()
+        .chain_err(|| format!("cannot run compiler {:?}", compiler_path))?;
+    let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || sysroot.is_empty() {
+        bail!(ErrorKind::CompilerInfoError(
+            "cannot obtain the Rust sysroot"
+        ));
+    }
+    Path::new(&sysroot)
+        .canonicalize()
+        .chain_err(|| format!("cannot resolve sysroot path {:?}", sysroot))
+}
+
+/// The compiler binary in the sysroot. The "rustc" program found in $PATH
+/// is often a rustup proxy, which does not work without the rest of the
+/// rustup installation.
+pub fn binary(sysroot: &Path) -> Option<PathBuf> {
+    Some(sysroot.join("bin/rustc")).filter(|path| path.is_file())
+}
+
+/// Shared libraries with the compiler itself, like "librustc_driver-*.so".
+/// They are found by scanning rustc as well, but are listed explicitly in
+/// case it loads them in some other way.
+pub fn driver_libraries(sysroot: &Path) -> Vec<PathBuf> {
+    let entries = match std::fs::read_dir(sysroot.join("lib")) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+    let mut libraries: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| {
+            path.is_file()
+                && path
+                    .file_name()
+                    .and_then(|name| name.to_str())
+                    .is_some_and(|name| name.starts_with("librustc_driver-"))
+        })
+        .collect();
+    libraries.sort();
+    libraries
+}
+
+/// Programs which rustc runs for the host, like "rust-lld" and its
+/// "gcc-ld" wrappers.
+pub fn host_tools(sysroot: &Path, host: &str) -> Vec<PathBuf> {
+    files(&target_dir(sysroot, host).join("bin"))
+}
+
+/// Adds the standard library of each target, which is needed to compile
+/// any crate for it.
+pub fn add_targets(solver: &mut Solver, sysroot: &Path, targets: &[String]) -> Result<()> {
+    for target in targets {
+        let dir = target_dir(sysroot, target).join("lib");
+        if !dir.is_dir() {
+            bail!("the Rust standard library for {} is not installed", target);
+        }
+        info!("Rust standard library for {}: {:?}", target, dir);
+        crate::sysroot::add_tree(solver, Path::new("/"), &dir, &|_| true)?;
+    }
+    Ok(())
+}
+
+fn target_dir(sysroot: &Path, target: &str) -> PathBuf {
+    sysroot.join("lib/rustlib").join(target)
+}
+
+/// Regular files under a directory, recursively, sorted.
+fn files(dir: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+    for path in entries.filter_map(|entry| Some(entry.ok()?.path())) {
+        if path.is_dir() {
+            files.extend(self::files(&path));
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    files.sort();
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempdir::TempDir;
+
+    fn touch(root: &Path, path: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"").unwrap();
+    }
+
+    #[test]
+    fn sysroot_contents() {
+        let dir = TempDir::new("rust-sysroot").unwrap();
+        let root = dir.path();
+        touch(root, "bin/rustc");
+        touch(root, "lib/librustc_driver-0123456789abcdef.so");
+        touch(root, "lib/libLLVM.so.17-rust-1.75.0-stable");
+        let host = "lib/rustlib/x86_64-unknown-linux-gnu";
+        touch(root, &format!("{}/bin/rust-lld", host));
+        touch(root, &format!("{}/bin/gcc-ld/ld.lld", host));
+        touch(root, &format!("{}/lib/libstd-0123456789abcdef.rlib", host));
+
+        assert_eq!(Some(root.join("bin/rustc")), binary(root));
+        assert_eq!(
+            vec![root.join("lib/librustc_driver-0123456789abcdef.so")],
+            driver_libraries(root)
+        );
+        assert_eq!(
+            vec![
+                root.join(host).join("bin/gcc-ld/ld.lld"),
+                root.join(host).join("bin/rust-lld"),
+            ],
+            host_tools(root, "x86_64-unknown-linux-gnu")
+        );
+
+        let mut solver = Solver::new();
+        add_targets(&mut solver, root, &["x86_64-unknown-linux-gnu".to_string()]).unwrap();
+        assert!(add_targets(&mut solver, root, &["wasm32-unknown-unknown".to_string()]).is_err());
+        assert!(solver.entries().iter().any(|entry| entry.source()
+            == Some(&root.join(host).join("lib/libstd-0123456789abcdef.rlib"))));
+    }
+}
@@ -0,0 +1,82 @@
+//
+// sccache.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use crate::layout::Layout;
+
+/// Path of the compiler when the archive is unpacked as the root of the
+/// build environment, which sccache-dist calls the "archive compiler
+/// executable".
+pub fn archive_compiler_path(layout: Layout, compiler_path: &Path) -> PathBuf {
+    Path::new("/").join(layout.place(compiler_path))
+}
+
+/// Entry for the "dist.toolchains" list of the sccache configuration, which
+/// makes sccache send the archive instead of packaging the compiler itself.
+pub fn toolchain_config(compiler_path: &Path, archive: &Path, archive_compiler: &Path) -> String {
+    let mut config = String::from("[[dist.toolchains]]\ntype = \"path_override\"\n");
+    for (key, path) in &[
+        ("compiler_executable", compiler_path),
+        ("archive", archive),
+        ("archive_compiler_executable", archive_compiler),
+    ] {
+        writeln!(config, "{} = {}", key, toml_string(&path.to_string_lossy())).unwrap();
+    }
+    config
+}
+
+/// Quotes a string as a TOML basic string.
+fn toml_string(s: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            c if c.is_control() => write!(quoted, "\\u{:04X}", c as u32).unwrap(),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_compiler_follows_layout() {
+        let gcc = Path::new("/usr/bin/gcc");
+        assert_eq!(
+            PathBuf::from("/bin/gcc"),
+            archive_compiler_path(Layout::Merged, gcc)
+        );
+        assert_eq!(
+            PathBuf::from("/usr/bin/gcc"),
+            archive_compiler_path(Layout::Split, gcc)
+        );
+    }
+
+    #[test]
+    fn toolchain_config_entry() {
+        let config = toolchain_config(
+            Path::new("/usr/bin/gcc"),
+            Path::new("/srv/\"toolchains\"/0123.tar.gz"),
+            Path::new("/bin/gcc"),
+        );
+        assert_eq!(
+            "[[dist.toolchains]]\n\
+             type = \"path_override\"\n\
+             compiler_executable = \"/usr/bin/gcc\"\n\
+             archive = \"/srv/\\\"toolchains\\\"/0123.tar.gz\"\n\
+             archive_compiler_executable = \"/bin/gcc\"\n",
+            config
+        );
+        assert_eq!("\"a\\\\b\\u0009\"", toml_string("a\\b\t"));
+    }
+}
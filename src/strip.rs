@@ -0,0 +1,396 @@
+//
+// strip.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use goblin::elf::header::{ET_DYN, ET_EXEC};
+use goblin::elf::section_header::{
+    SectionHeader, SHF_ALLOC, SHF_INFO_LINK, SHT_NOBITS, SHT_REL, SHT_RELA, SHT_SYMTAB,
+};
+use goblin::elf::Elf;
+use log::debug;
+
+/// Writes integers with the layout of a particular ELF file.
+struct Layout {
+    is_64: bool,
+    little_endian: bool,
+}
+
+impl Layout {
+    fn word(&self) -> usize {
+        if self.is_64 {
+            8
+        } else {
+            4
+        }
+    }
+
+    fn put(&self, out: &mut Vec<u8>, value: u64, size: usize) {
+        let bytes = value.to_le_bytes();
+        if self.little_endian {
+            out.extend(&bytes[..size]);
+        } else {
+            out.extend(bytes[..size].iter().rev());
+        }
+    }
+
+    fn patch(&self, out: &mut [u8], offset: usize, value: u64, size: usize) {
+        let mut bytes = Vec::with_capacity(size);
+        self.put(&mut bytes, value, size);
+        out[offset..offset + size].copy_from_slice(&bytes);
+    }
+
+    fn section_header(&self, out: &mut Vec<u8>, sh: &SectionHeader) {
+        let word = self.word();
+        self.put(out, sh.sh_name as u64, 4);
+        self.put(out, sh.sh_type as u64, 4);
+        self.put(out, sh.sh_flags, word);
+        self.put(out, sh.sh_addr, word);
+        self.put(out, sh.sh_offset, word);
+        self.put(out, sh.sh_size, word);
+        self.put(out, sh.sh_link as u64, 4);
+        self.put(out, sh.sh_info as u64, 4);
+        self.put(out, sh.sh_addralign, word);
+        self.put(out, sh.sh_entsize, word);
+    }
+
+    /// Offsets of e_shoff, e_shnum and e_shstrndx in the ELF header.
+    fn header_offsets(&self) -> (usize, usize, usize) {
+        if self.is_64 {
+            (0x28, 0x3C, 0x3E)
+        } else {
+            (0x20, 0x30, 0x32)
+        }
+    }
+}
+
+fn is_debug(name: &str) -> bool {
+    name.starts_with(".debug") || name.starts_with(".zdebug")
+}
+
+fn align(value: usize, alignment: u64) -> usize {
+    let alignment = alignment.max(1) as usize;
+    value.div_ceil(alignment) * alignment
+}
+
+/// Finds which sections can be removed: debug information, the symbol
+/// table and its string table, and relocations which apply to them.
+fn removable_sections(elf: &Elf) -> Vec<bool> {
+    let sections = &elf.section_headers;
+    let shstrndx = elf.header.e_shstrndx as usize;
+    let name = |sh: &SectionHeader| match elf.shdr_strtab.get(sh.sh_name) {
+        Some(Ok(name)) => name,
+        _ => "",
+    };
+
+    let mut remove: Vec<bool> = sections
+        .iter()
+        .map(|sh| {
+            sh.sh_flags & SHF_ALLOC as u64 == 0 && (sh.sh_type == SHT_SYMTAB || is_debug(name(sh)))
+        })
+        .collect();
+    for sh in sections.iter().filter(|sh| sh.sh_type == SHT_SYMTAB) {
+        let link = sh.sh_link as usize;
+        if link != shstrndx
+            && link < sections.len()
+            && sections[link].sh_flags & SHF_ALLOC as u64 == 0
+        {
+            remove[link] = true;
+        }
+    }
+    for (index, sh) in sections.iter().enumerate() {
+        if (sh.sh_type == SHT_REL || sh.sh_type == SHT_RELA) && sh.sh_flags & SHF_ALLOC as u64 == 0
+        {
+            let (link, info) = (sh.sh_link as usize, sh.sh_info as usize);
+            if remove.get(link) == Some(&true) || remove.get(info) == Some(&true) {
+                remove[index] = true;
+            }
+        }
+    }
+    remove[0] = false;
+    remove
+}
+
+/// Removes debug sections and symbol tables from an ELF executable or
+/// shared object. Returns nothing for other files, including relocatable
+/// objects, which need their symbols and relocations to be linked, or if
+/// there is nothing to remove.
+///
+/// Everything covered by the program headers is kept in place, so the
+/// result can be loaded exactly like the original. Sections after it
+/// are packed again, followed by a new section header table.
+pub fn strip(data: &[u8]) -> Option<Vec<u8>> {
+    let elf = Elf::parse(data).ok()?;
+    let header = &elf.header;
+    if header.e_type != ET_EXEC && header.e_type != ET_DYN {
+        return None;
+    }
+    let sections = &elf.section_headers;
+    // Extended section numbering is not supported.
+    if sections.is_empty() || header.e_shnum == 0 || header.e_shstrndx as usize >= sections.len() {
+        return None;
+    }
+
+    let remove = removable_sections(&elf);
+    if !remove.iter().any(|&r| r) {
+        return None;
+    }
+
+    let mut index_map = Vec::with_capacity(sections.len());
+    let mut count = 0;
+    for &removed in &remove {
+        index_map.push(if removed { None } else { Some(count) });
+        count += usize::from(!removed);
+    }
+    let remap = |index: u32| {
+        index_map
+            .get(index as usize)
+            .cloned()
+            .flatten()
+            .unwrap_or(0) as u32
+    };
+
+    // Everything up to the end of the last segment is kept as-is.
+    let phdrs_end = header.e_phoff as usize + header.e_phnum as usize * header.e_phentsize as usize;
+    let mut base = (header.e_ehsize as usize).max(phdrs_end);
+    for ph in &elf.program_headers {
+        base = base.max((ph.p_offset + ph.p_filesz) as usize);
+    }
+    for (sh, &removed) in sections.iter().zip(&remove) {
+        if !removed && sh.sh_flags & SHF_ALLOC as u64 != 0 && sh.sh_type != SHT_NOBITS {
+            base = base.max((sh.sh_offset + sh.sh_size) as usize);
+        }
+    }
+    if base > data.len() {
+        return None;
+    }
+
+    let layout = Layout {
+        is_64: elf.is_64,
+        little_endian: elf.little_endian,
+    };
+    let mut out = data[..base].to_vec();
+    let mut kept = Vec::with_capacity(count);
+    let mut order: Vec<usize> = (0..sections.len()).filter(|&i| !remove[i]).collect();
+    order.sort_by_key(|&i| sections[i].sh_offset);
+    let mut new_offsets = vec![0u64; sections.len()];
+    for index in order {
+        let sh = &sections[index];
+        let offset = sh.sh_offset as usize;
+        new_offsets[index] = if index == 0 || offset < base {
+            sh.sh_offset
+        } else if sh.sh_type == SHT_NOBITS {
+            out.len() as u64
+        } else {
+            let end = offset.checked_add(sh.sh_size as usize)?;
+            if end > data.len() {
+                return None;
+            }
+            out.resize(align(out.len(), sh.sh_addralign), 0);
+            let new_offset = out.len() as u64;
+            out.extend_from_slice(&data[offset..end]);
+            new_offset
+        };
+    }
+    for (index, sh) in sections.iter().enumerate() {
+        if remove[index] {
+            continue;
+        }
+        let mut sh = sh.clone();
+        sh.sh_offset = new_offsets[index];
+        sh.sh_link = remap(sh.sh_link);
+        if sh.sh_type == SHT_REL
+            || sh.sh_type == SHT_RELA
+            || sh.sh_flags & SHF_INFO_LINK as u64 != 0
+        {
+            sh.sh_info = remap(sh.sh_info);
+        }
+        kept.push(sh);
+    }
+
+    out.resize(align(out.len(), layout.word() as u64), 0);
+    let shoff = out.len() as u64;
+    for sh in &kept {
+        layout.section_header(&mut out, sh);
+    }
+    let (shoff_offset, shnum_offset, shstrndx_offset) = layout.header_offsets();
+    let word = layout.word();
+    layout.patch(&mut out, shoff_offset, shoff, word);
+    layout.patch(&mut out, shnum_offset, kept.len() as u64, 2);
+    layout.patch(
+        &mut out,
+        shstrndx_offset,
+        remap(header.e_shstrndx as u32) as u64,
+        2,
+    );
+
+    debug!("stripped {} bytes", data.len().saturating_sub(out.len()));
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHT_PROGBITS: u32 = 1;
+    const SHT_STRTAB: u32 = 3;
+
+    /// Builds a small executable with one loadable segment containing
+    /// ".text", followed by non-loadable sections.
+    fn build_elf(is_64: bool, little_endian: bool) -> Vec<u8> {
+        let layout = Layout {
+            is_64,
+            little_endian,
+        };
+        let word = layout.word();
+        let (ehsize, phentsize, shentsize) = if is_64 { (64, 56, 64) } else { (52, 32, 40) };
+
+        let shstrtab = b"\0.text\0.debug_info\0.symtab\0.strtab\0.comment\0.shstrtab\0";
+        let names = [0, 1, 7, 19, 27, 35, 44];
+        let contents: [(&str, u32, u64, &[u8]); 6] = [
+            (".text", SHT_PROGBITS, SHF_ALLOC as u64, b"\x90\x90\x90\xc3"),
+            (".debug_info", SHT_PROGBITS, 0, &[0xdb; 100]),
+            (".symtab", SHT_SYMTAB, 0, &[0; 48]),
+            (".strtab", SHT_STRTAB, 0, b"\0main\0"),
+            (".comment", SHT_PROGBITS, 0x30, b"GCC: 12\0"),
+            (".shstrtab", SHT_STRTAB, 0, shstrtab),
+        ];
+
+        let mut out = Vec::new();
+        out.extend(b"\x7fELF");
+        out.push(if is_64 { 2 } else { 1 });
+        out.push(if little_endian { 1 } else { 2 });
+        out.extend(&[1, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        layout.put(&mut out, 2, 2); // ET_EXEC
+        layout.put(&mut out, if is_64 { 62 } else { 3 }, 2);
+        layout.put(&mut out, 1, 4);
+        layout.put(&mut out, 0x1000, word); // e_entry
+        layout.put(&mut out, ehsize as u64, word); // e_phoff
+        layout.put(&mut out, 0, word); // e_shoff, patched below
+        layout.put(&mut out, 0, 4);
+        layout.put(&mut out, ehsize as u64, 2);
+        layout.put(&mut out, phentsize as u64, 2);
+        layout.put(&mut out, 1, 2);
+        layout.put(&mut out, shentsize as u64, 2);
+        layout.put(&mut out, contents.len() as u64 + 1, 2);
+        layout.put(&mut out, contents.len() as u64, 2);
+
+        let text_offset = (ehsize + phentsize) as u64;
+        let text_size = contents[0].3.len() as u64;
+        layout.put(&mut out, 1, 4); // PT_LOAD
+        if is_64 {
+            layout.put(&mut out, 5, 4);
+        }
+        layout.put(&mut out, 0, word);
+        layout.put(&mut out, 0x1000 - text_offset, word);
+        layout.put(&mut out, 0x1000 - text_offset, word);
+        layout.put(&mut out, text_offset + text_size, word);
+        layout.put(&mut out, text_offset + text_size, word);
+        if !is_64 {
+            layout.put(&mut out, 5, 4);
+        }
+        layout.put(&mut out, 0x1000, word);
+
+        let mut headers = vec![SectionHeader::new()];
+        for (index, (name, sh_type, flags, data)) in contents.iter().enumerate() {
+            let mut sh = SectionHeader::new();
+            sh.sh_name = names[index + 1];
+            sh.sh_type = *sh_type;
+            sh.sh_flags = *flags;
+            sh.sh_offset = out.len() as u64;
+            sh.sh_size = data.len() as u64;
+            sh.sh_addralign = 1;
+            if *name == ".text" {
+                sh.sh_addr = 0x1000;
+            }
+            if *sh_type == SHT_SYMTAB {
+                sh.sh_link = 4;
+                sh.sh_info = 1;
+                sh.sh_entsize = if is_64 { 24 } else { 16 };
+            }
+            out.extend(*data);
+            headers.push(sh);
+        }
+        let shoff = out.len() as u64;
+        for sh in &headers {
+            layout.section_header(&mut out, sh);
+        }
+        layout.patch(&mut out, layout.header_offsets().0, shoff, word);
+        out
+    }
+
+    fn section_names(data: &[u8]) -> Vec<String> {
+        let elf = Elf::parse(data).unwrap();
+        elf.section_headers
+            .iter()
+            .map(|sh| {
+                elf.shdr_strtab
+                    .get(sh.sh_name)
+                    .unwrap()
+                    .unwrap()
+                    .to_string()
+            })
+            .collect()
+    }
+
+    fn section_data<'a>(data: &'a [u8], name: &str) -> &'a [u8] {
+        let elf = Elf::parse(data).unwrap();
+        let sh = elf
+            .section_headers
+            .iter()
+            .find(|sh| elf.shdr_strtab.get(sh.sh_name).unwrap().unwrap() == name)
+            .unwrap();
+        &data[sh.sh_offset as usize..(sh.sh_offset + sh.sh_size) as usize]
+    }
+
+    #[test]
+    fn strips_debug_and_symbols() {
+        for &(is_64, little_endian) in &[(true, true), (false, true), (true, false)] {
+            let original = build_elf(is_64, little_endian);
+            assert_eq!(
+                vec![
+                    "",
+                    ".text",
+                    ".debug_info",
+                    ".symtab",
+                    ".strtab",
+                    ".comment",
+                    ".shstrtab"
+                ],
+                section_names(&original)
+            );
+
+            let stripped = strip(&original).unwrap();
+            assert!(stripped.len() < original.len());
+            assert_eq!(
+                vec!["", ".text", ".comment", ".shstrtab"],
+                section_names(&stripped)
+            );
+            assert_eq!(
+                section_data(&original, ".text"),
+                section_data(&stripped, ".text")
+            );
+            assert_eq!(b"GCC: 12\0", section_data(&stripped, ".comment"));
+            // The loadable part of the file after the ELF header is untouched.
+            let text = section_data(&original, ".text");
+            let loaded = text.as_ptr() as usize + text.len() - original.as_ptr() as usize;
+            let ehsize = if is_64 { 64 } else { 52 };
+            assert_eq!(&original[ehsize..loaded], &stripped[ehsize..loaded]);
+
+            // Nothing else to remove the second time.
+            assert_eq!(None, strip(&stripped));
+        }
+    }
+
+    #[test]
+    fn ignores_other_files() {
+        assert_eq!(None, strip(b"#!/bin/sh\necho hello\n"));
+        assert_eq!(None, strip(b""));
+
+        // Relocatable objects (ET_REL) are left alone.
+        let mut object = build_elf(true, true);
+        object[16] = 1;
+        assert_eq!(None, strip(&object));
+    }
+}
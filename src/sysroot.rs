@@ -0,0 +1,241 @@
+//
+// sysroot.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+use log::{debug, info, warn};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+
+use crate::bindep::Solver;
+use crate::errors::*;
+
+/// Which parts of the compiler sysroot get packaged.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// Do not package the sysroot.
+    None,
+    /// Headers, startup files and specs, which are needed when compiling.
+    Compile,
+    /// The complete sysroot, which is needed when linking as well.
+    Full,
+}
+
+impl Mode {
+    pub const NAMES: &'static [&'static str] = &["none", "compile", "full"];
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Mode::None),
+            "compile" => Ok(Mode::Compile),
+            "full" => Ok(Mode::Full),
+            _ => Err(format!("unsupported sysroot mode: {}", s)),
+        }
+    }
+}
+
+/// Directories with headers, relative to the sysroot.
+const INCLUDE_DIRS: &[&str] = &["include", "usr/include", "usr/local/include"];
+
+/// Directories with startup files, relative to the sysroot. Their
+/// immediate subdirectories are also checked, for multiarch layouts.
+const LIB_DIRS: &[&str] = &["lib", "lib32", "lib64", "usr/lib", "usr/lib32", "usr/lib64"];
+
+/// Asks the compiler for its sysroot. Returns nothing when the compiler
+/// does not use one, when it is the host root directory, or when it is not
+/// installed (it may not be needed, e.g. with "--sysroot none").
+pub fn detect(compiler_path: &Path) -> Result<Option<PathBuf>> {
+    let output = Command::new(compiler_path)
+        .arg("-print-sysroot")
+        .env("LC_ALL", "C")
+        .output()
+        .chain_err(|| format!("cannot run compiler {:?}", compiler_path))?;
+    if !output.status.success() {
+        debug!("{:?} does not support -print-sysroot", compiler_path);
+        return Ok(None);
+    }
+
+    let sysroot = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if sysroot.is_empty() {
+        return Ok(None);
+    }
+    let sysroot = match Path::new(&sysroot).canonicalize() {
+        Ok(sysroot) => sysroot,
+        Err(e) => {
+            warn!("ignoring sysroot {:?}: {}", sysroot, e);
+            return Ok(None);
+        }
+    };
+    if sysroot == Path::new("/") {
+        Ok(None)
+    } else {
+        Ok(Some(sysroot))
+    }
+}
+
+fn is_compile_file(path: &Path) -> bool {
+    match path.file_name().and_then(|name| name.to_str()) {
+        Some(name) => name == "specs" || (name.starts_with("crt") && name.ends_with(".o")),
+        None => false,
+    }
+}
+
+/// Adds the parts of the sysroot selected by the mode to the solver.
+/// Files keep their absolute path, which is where the compiler will look
+/// for them, and absolute symbolic link targets are made to point inside
+/// the sysroot.
+pub fn add(solver: &mut Solver, sysroot: &Path, mode: Mode) -> Result<()> {
+    match mode {
+        Mode::None => {
+            info!("not packaging sysroot {:?}", sysroot);
+        }
+        Mode::Compile => {
+            for dir in INCLUDE_DIRS {
+                add_tree(solver, sysroot, &sysroot.join(dir), &|_| true)?;
+            }
+            for dir in LIB_DIRS {
+                let dir = sysroot.join(dir);
+                add_tree(solver, sysroot, &dir, &|path| {
+                    path.parent().is_some_and(|parent| {
+                        parent == dir || parent.parent() == Some(dir.as_path())
+                    }) && is_compile_file(path)
+                })?;
+            }
+        }
+        Mode::Full => add_tree(solver, sysroot, sysroot, &|_| true)?,
+    }
+    Ok(())
+}
+
+/// Adds the files under a directory, with absolute symbolic link targets
+/// made to point inside the sysroot.
+pub fn add_tree(
+    solver: &mut Solver,
+    sysroot: &Path,
+    dir: &Path,
+    filter: &dyn Fn(&Path) -> bool,
+) -> Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    let mut entries = std::fs::read_dir(dir)
+        .chain_err(|| format!("cannot read directory {:?}", dir))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    // Keep the order stable, for reproducible tarballs.
+    entries.sort();
+
+    for path in entries {
+        let file_type = path.symlink_metadata()?.file_type();
+        if file_type.is_dir() {
+            add_tree(solver, sysroot, &path, filter)?;
+        } else if !filter(&path) {
+            continue;
+        } else if file_type.is_symlink() {
+            let target = std::fs::read_link(&path)?;
+            let target = match target.strip_prefix("/") {
+                Ok(relative) => sysroot.join(relative),
+                Err(_) => target,
+            };
+            solver.symlink(target.as_path(), path.strip_prefix("/").unwrap());
+        } else if file_type.is_file() {
+            solver.add_file(&path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bindep::Entry;
+    use tempdir::TempDir;
+
+    fn touch(root: &Path, path: &str) {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, b"").unwrap();
+    }
+
+    fn sysroot() -> TempDir {
+        let dir = TempDir::new("sysroot").unwrap();
+        let root = dir.path();
+        touch(root, "usr/include/stdio.h");
+        touch(root, "usr/include/sys/types.h");
+        touch(root, "usr/lib/crt1.o");
+        touch(root, "usr/lib/aarch64-linux-gnu/crti.o");
+        touch(root, "usr/lib/libc.so.6");
+        touch(root, "usr/lib/gcc/aarch64-linux-gnu/10/deep/crtn.o");
+        std::os::unix::fs::symlink("/usr/lib/libc.so.6", root.join("usr/lib/libc.so")).unwrap();
+        dir
+    }
+
+    fn entries(root: &Path, mode: Mode) -> Vec<String> {
+        let mut solver = Solver::new();
+        add(&mut solver, root, mode).unwrap();
+        solver
+            .entries()
+            .iter()
+            .filter(|entry| match entry {
+                Entry::File { .. } | Entry::Hardlink { .. } => true,
+                Entry::Symlink { path, .. } => path.starts_with(root.strip_prefix("/").unwrap()),
+                Entry::Data { .. } => false,
+            })
+            .map(|entry| {
+                let path = entry
+                    .tar_path()
+                    .strip_prefix(root.strip_prefix("/").unwrap());
+                path.unwrap().to_str().unwrap().to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn mode_parsing() {
+        for name in Mode::NAMES {
+            assert!(name.parse::<Mode>().is_ok());
+        }
+        assert!("partial".parse::<Mode>().is_err());
+    }
+
+    #[test]
+    fn compile_mode() {
+        let dir = sysroot();
+        assert_eq!(
+            vec![
+                "usr/include/stdio.h",
+                "usr/include/sys/types.h",
+                "usr/lib/aarch64-linux-gnu/crti.o",
+                "usr/lib/crt1.o",
+            ],
+            entries(dir.path(), Mode::Compile)
+        );
+    }
+
+    #[test]
+    fn full_mode() {
+        let dir = sysroot();
+        let root = dir.path();
+        assert_eq!(7, entries(root, Mode::Full).len());
+
+        let mut solver = Solver::new();
+        add(&mut solver, root, Mode::Full).unwrap();
+        let target = solver.entries().iter().find_map(|entry| match entry {
+            Entry::Symlink { target, path } if path.ends_with("libc.so") => Some(target.clone()),
+            _ => None,
+        });
+        assert_eq!(Some(root.join("usr/lib/libc.so.6")), target);
+    }
+
+    #[test]
+    fn none_mode() {
+        let dir = sysroot();
+        assert!(entries(dir.path(), Mode::None).is_empty());
+    }
+}
@@ -6,46 +6,11 @@
 
 use crate::errors::*;
 use error_chain::bail;
-use lazy_static::lazy_static;
 use log::warn;
-use regex::bytes::Regex;
 use std::convert::AsRef;
+use std::io::Read;
 use std::os::unix::prelude::MetadataExt;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-
-#[derive(Debug, Clone, Copy)]
-pub enum CompilerKind {
-    Gcc,
-    Clang,
-}
-
-const NL: u8 = 0x0A;
-
-pub fn compiler_info(path: &::std::ffi::OsStr) -> Result<(CompilerKind, String, String)> {
-    lazy_static! {
-        static ref RE: Regex = Regex::new(r"^(clang|gcc)\s+version\s+([\d\.]+)").unwrap();
-    }
-
-    let output = Command::new(path).arg("-v").output()?;
-    let out = output.stdout.as_slice();
-    let err = output.stderr.as_slice();
-
-    for line in out.split(|&c| c == NL).chain(err.split(|&c| c == NL)) {
-        if let Some(cap) = RE.captures(line) {
-            let name = ::std::str::from_utf8(&cap[1]).unwrap();
-            let version = ::std::str::from_utf8(&cap[2]).unwrap();
-            let kind = match name {
-                "gcc" => CompilerKind::Gcc,
-                "clang" => CompilerKind::Clang,
-                _ => unreachable!(),
-            };
-            return Ok((kind, name.to_string(), version.to_string()));
-        }
-    }
-
-    Err(ErrorKind::CompilerInfoError("no version information").into())
-}
 
 pub fn find_program<P: AsRef<Path>>(name: P, symlink_target: Option<&PathBuf>) -> Result<PathBuf> {
     let name_path = name.as_ref();
@@ -66,7 +31,7 @@ pub fn find_program<P: AsRef<Path>>(name: P, symlink_target: Option<&PathBuf>) -
 
     for path in ::std::env::split_paths(search_paths.as_str()) {
         if path.is_absolute() {
-            let full_path: PathBuf = [&path, name_path].into_iter().collect();
+            let full_path: PathBuf = [&path, name_path].iter().collect();
             // TODO: Also check that the file is executable (st_mode?)
             if let Some(target_dev_ino) = target_dev_ino {
                 let is_symlink = full_path
@@ -94,3 +59,13 @@ pub fn find_program<P: AsRef<Path>>(name: P, symlink_target: Option<&PathBuf>) -
     }
     bail!(ErrorKind::ExternalExeError(name_path.to_path_buf()))
 }
+
+/// Reads at most "len" bytes from the start of a file, e.g. to check its
+/// magic number without reading all of it.
+pub fn read_head(path: &Path, len: usize) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::with_capacity(len);
+    std::fs::File::open(path)?
+        .take(len as u64)
+        .read_to_end(&mut head)?;
+    Ok(head)
+}
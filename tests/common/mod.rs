@@ -0,0 +1,392 @@
+//
+// common/mod.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use tempdir::TempDir;
+
+const DT_NULL: u64 = 0;
+const DT_NEEDED: u64 = 1;
+const DT_STRTAB: u64 = 5;
+const DT_STRSZ: u64 = 10;
+const DT_SONAME: u64 = 14;
+const DT_RPATH: u64 = 15;
+const DT_RUNPATH: u64 = 29;
+
+pub const EM_386: u16 = 3;
+pub const EM_X86_64: u16 = 62;
+pub const EM_AARCH64: u16 = 183;
+
+/// Builds minimal ELF files which contain only what is needed to describe
+/// their dynamic dependencies. They cannot be run, only inspected.
+#[derive(Clone)]
+pub struct ElfBuilder {
+    class64: bool,
+    machine: u16,
+    needed: Vec<String>,
+    soname: Option<String>,
+    rpath: Option<String>,
+    runpath: Option<String>,
+}
+
+impl ElfBuilder {
+    pub fn new() -> Self {
+        Self {
+            class64: true,
+            machine: EM_X86_64,
+            needed: Vec::new(),
+            soname: None,
+            rpath: None,
+            runpath: None,
+        }
+    }
+
+    pub fn class32(mut self) -> Self {
+        self.class64 = false;
+        self.machine = EM_386;
+        self
+    }
+
+    pub fn machine(mut self, machine: u16) -> Self {
+        self.machine = machine;
+        self
+    }
+
+    pub fn needed(mut self, name: &str) -> Self {
+        self.needed.push(name.to_string());
+        self
+    }
+
+    pub fn soname(mut self, name: &str) -> Self {
+        self.soname = Some(name.to_string());
+        self
+    }
+
+    pub fn rpath(mut self, path: &str) -> Self {
+        self.rpath = Some(path.to_string());
+        self
+    }
+
+    pub fn runpath(mut self, path: &str) -> Self {
+        self.runpath = Some(path.to_string());
+        self
+    }
+
+    pub fn build(&self) -> Vec<u8> {
+        let (ehsize, phentsize, word) = if self.class64 {
+            (64, 56, 8)
+        } else {
+            (52, 32, 4)
+        };
+        let strtab_offset = ehsize + 2 * phentsize;
+
+        let mut strtab = vec![0u8];
+        let mut dyns = Vec::new();
+        let mut add_string = |tag: u64, value: &str, dyns: &mut Vec<(u64, u64)>| {
+            dyns.push((tag, strtab.len() as u64));
+            strtab.extend_from_slice(value.as_bytes());
+            strtab.push(0);
+        };
+        for name in &self.needed {
+            add_string(DT_NEEDED, name, &mut dyns);
+        }
+        if let Some(ref name) = self.soname {
+            add_string(DT_SONAME, name, &mut dyns);
+        }
+        if let Some(ref path) = self.rpath {
+            add_string(DT_RPATH, path, &mut dyns);
+        }
+        if let Some(ref path) = self.runpath {
+            add_string(DT_RUNPATH, path, &mut dyns);
+        }
+        dyns.push((DT_STRTAB, strtab_offset as u64));
+        dyns.push((DT_STRSZ, strtab.len() as u64));
+        dyns.push((DT_NULL, 0));
+
+        let dynamic_offset = (strtab_offset + strtab.len() + 7) & !7;
+        let dynamic_size = dyns.len() * 2 * word;
+        let shoff = dynamic_offset + dynamic_size;
+        let shentsize = if self.class64 { 64 } else { 40 };
+        let file_size = shoff + shentsize;
+
+        let mut out = Vec::with_capacity(file_size);
+        let mut elf = ElfWriter {
+            out: &mut out,
+            class64: self.class64,
+        };
+
+        // ELF header.
+        elf.bytes(&[0x7F, b'E', b'L', b'F']);
+        elf.bytes(&[if self.class64 { 2 } else { 1 }, 1, 1, 0]);
+        elf.bytes(&[0; 8]);
+        elf.u16(3); // ET_DYN
+        elf.u16(self.machine);
+        elf.u32(1);
+        elf.word(0); // e_entry
+        elf.word(ehsize as u64); // e_phoff
+        elf.word(shoff as u64);
+        elf.u32(0);
+        elf.u16(ehsize as u16);
+        elf.u16(phentsize as u16);
+        elf.u16(2);
+        elf.u16(shentsize as u16);
+        elf.u16(1);
+        elf.u16(0);
+
+        // Program headers: a PT_LOAD covering the whole file, and PT_DYNAMIC.
+        elf.phdr(1, 4, 0, file_size as u64, 0x1000);
+        elf.phdr(
+            2,
+            6,
+            dynamic_offset as u64,
+            dynamic_size as u64,
+            word as u64,
+        );
+
+        elf.bytes(&strtab);
+        while elf.out.len() < dynamic_offset {
+            elf.bytes(&[0]);
+        }
+        for (tag, value) in dyns {
+            elf.word(tag);
+            elf.word(value);
+        }
+
+        // The null section header, which is always present.
+        elf.bytes(&vec![0; shentsize]);
+        out
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) {
+        let path = path.as_ref();
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, self.build()).unwrap();
+        fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+    }
+}
+
+/// Builds a minimal 64-bit relocatable object, like the startup files of a
+/// sysroot, with a symbol table and relocations for its code.
+pub fn relocatable_object() -> Vec<u8> {
+    let text: &[u8] = b"\xe8\0\0\0\0\xc3\x90\x90"; // call main; ret
+    let strtab: &[u8] = b"\0main\0";
+    let shstrtab: &[u8] = b"\0.text\0.rela.text\0.symtab\0.strtab\0.shstrtab\0";
+    let (ehsize, shentsize) = (64, 64);
+    let text_offset = ehsize;
+    let rela_offset = text_offset + text.len();
+    let symtab_offset = rela_offset + 24;
+    let strtab_offset = symtab_offset + 2 * 24;
+    let shstrtab_offset = strtab_offset + strtab.len();
+    let shoff = (shstrtab_offset + shstrtab.len() + 7) & !7;
+
+    let mut out = Vec::new();
+    let mut elf = ElfWriter {
+        out: &mut out,
+        class64: true,
+    };
+    elf.bytes(&[0x7F, b'E', b'L', b'F', 2, 1, 1, 0]);
+    elf.bytes(&[0; 8]);
+    elf.u16(1); // ET_REL
+    elf.u16(EM_X86_64);
+    elf.u32(1);
+    elf.word(0); // e_entry
+    elf.word(0); // e_phoff
+    elf.word(shoff as u64);
+    elf.u32(0);
+    elf.u16(ehsize as u16);
+    elf.u16(0);
+    elf.u16(0);
+    elf.u16(shentsize as u16);
+    elf.u16(6);
+    elf.u16(5);
+
+    elf.bytes(text);
+    // R_X86_64_PLT32 against "main", for the operand of the call.
+    elf.word(1);
+    elf.word((1 << 32) | 4);
+    elf.word(-4i64 as u64);
+    elf.bytes(&[0; 24]);
+    elf.u32(1); // st_name
+    elf.bytes(&[0x10, 0]); // STB_GLOBAL, STT_NOTYPE
+    elf.u16(0); // SHN_UNDEF
+    elf.word(0);
+    elf.word(0);
+    elf.bytes(strtab);
+    elf.bytes(shstrtab);
+    while elf.out.len() < shoff {
+        elf.bytes(&[0]);
+    }
+
+    // (name, type, flags, offset, size, link, info, align, entsize)
+    let sections = [
+        (0, 0, 0, 0, 0, 0, 0, 0, 0),
+        (1, 1, 6, text_offset, text.len(), 0, 0, 16, 0),
+        (7, 4, 0x40, rela_offset, 24, 3, 1, 8, 24),
+        (18, 2, 0, symtab_offset, 48, 4, 1, 8, 24),
+        (26, 3, 0, strtab_offset, strtab.len(), 0, 0, 1, 0),
+        (34, 3, 0, shstrtab_offset, shstrtab.len(), 0, 0, 1, 0),
+    ];
+    for (name, kind, flags, offset, size, link, info, align, entsize) in sections {
+        elf.u32(name);
+        elf.u32(kind);
+        elf.word(flags);
+        elf.word(0); // sh_addr
+        elf.word(offset as u64);
+        elf.word(size as u64);
+        elf.u32(link);
+        elf.u32(info);
+        elf.word(align);
+        elf.word(entsize);
+    }
+    out
+}
+
+struct ElfWriter<'a> {
+    out: &'a mut Vec<u8>,
+    class64: bool,
+}
+
+impl<'a> ElfWriter<'a> {
+    fn bytes(&mut self, data: &[u8]) {
+        self.out.extend_from_slice(data);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn word(&mut self, value: u64) {
+        if self.class64 {
+            self.bytes(&value.to_le_bytes());
+        } else {
+            self.u32(value as u32);
+        }
+    }
+
+    fn phdr(&mut self, kind: u32, flags: u32, offset: u64, size: u64, align: u64) {
+        self.u32(kind);
+        if self.class64 {
+            self.u32(flags);
+        }
+        self.word(offset); // p_offset
+        self.word(offset); // p_vaddr
+        self.word(offset); // p_paddr
+        self.word(size); // p_filesz
+        self.word(size); // p_memsz
+        if !self.class64 {
+            self.u32(flags);
+        }
+        self.word(align);
+    }
+}
+
+/// A temporary directory tree with fake compilers and ELF fixtures.
+pub struct Fixture {
+    pub dir: TempDir,
+}
+
+impl Fixture {
+    pub fn new() -> Self {
+        let dir = TempDir::new("popsicle-test").unwrap();
+        fs::create_dir_all(dir.path().join("bin")).unwrap();
+        fs::create_dir_all(dir.path().join("cache")).unwrap();
+        Self { dir }
+    }
+
+    pub fn path<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        self.dir.path().join(path)
+    }
+
+    pub fn elf<P: AsRef<Path>>(&self, path: P, builder: ElfBuilder) -> PathBuf {
+        let path = self.path(path);
+        builder.write(&path);
+        path
+    }
+
+    pub fn script<P: AsRef<Path>>(&self, path: P, body: &str) -> PathBuf {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, format!("#! /bin/sh\n{}", body)).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    /// Creates a fake compiler driver in "bin/<name>". The "-v" option prints
+    /// the given version line, and files and programs are looked up in the
+    /// "libexec" directory of the fixture.
+    pub fn compiler(&self, name: &str, version_line: &str, extra: &str) -> PathBuf {
+        let libexec = self.path("libexec");
+        self.script(
+            Path::new("bin").join(name),
+            &format!(
+                r#"libexec="{libexec}"
+lookup () {{
+    if [ -e "$libexec/$1" ] ; then echo "$libexec/$1" ; else echo "$2" ; fi
+}}
+case "$1" in
+    -v) echo "{version_line}" 1>&2 ;;
+    --print-file-name) lookup "$2" "$2" ;;
+    -print-file-name=*) lookup "${{1#*=}}" "${{1#*=}}" ;;
+    -print-prog-name=*) lookup "${{1#*=}}" "/nonexistent/${{1#*=}}" ;;
+{extra}
+esac
+"#,
+                libexec = libexec.display(),
+                version_line = version_line,
+                extra = extra,
+            ),
+        )
+    }
+
+    pub fn gcc(&self) -> PathBuf {
+        self.compiler("gcc", "gcc version 7.2.0 (Fake)", "")
+    }
+
+    pub fn clang(&self) -> PathBuf {
+        let cc1 = r#"    -###) echo ' "'"$0"'" "-cc1" "-emit-obj"' 1>&2 ;;"#;
+        self.compiler("clang", "clang version 6.0.0 (Fake)", cc1)
+    }
+
+    pub fn command(&self) -> Command {
+        let path = std::env::var("PATH").unwrap_or_default();
+        let mut command = Command::new(env!("CARGO_BIN_EXE_popsicle"));
+        command
+            .env("PATH", format!("{}:{}", self.path("bin").display(), path))
+            .env("XDG_CACHE_HOME", self.path("cache"))
+            .env_remove("RUST_BACKTRACE")
+            .env_remove("RUST_LOG");
+        command
+    }
+
+    pub fn run(&self, args: &[&str]) -> Output {
+        self.command().args(args).output().unwrap()
+    }
+
+    /// Runs popsicle, expecting success, and returns the lines of its output.
+    pub fn lines(&self, args: &[&str]) -> Vec<String> {
+        let output = self.run(args);
+        assert!(
+            output.status.success(),
+            "popsicle {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+}
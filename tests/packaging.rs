@@ -0,0 +1,732 @@
+//
+// packaging.rs
+// Copyright (C) 2017 Adrian Perez <aperez@igalia.com>
+// Distributed under terms of the MIT license.
+//
+
+mod common;
+
+use pretty_assertions::assert_eq;
+use std::path::Path;
+
+use crate::common::{ElfBuilder, Fixture, EM_AARCH64};
+
+fn gcc_fixture() -> Fixture {
+    let fixture = Fixture::new();
+    fixture.gcc();
+    fixture.elf("libexec/as", ElfBuilder::new());
+    fixture.elf(
+        "libexec/cc1",
+        ElfBuilder::new()
+            .needed("libfoo.so.1")
+            .runpath("$ORIGIN/../lib"),
+    );
+    fixture.elf(
+        "lib/libfoo.so.1",
+        ElfBuilder::new()
+            .soname("libfoo.so.1")
+            .needed("libbar.so.2")
+            .runpath("$ORIGIN"),
+    );
+    fixture.elf("lib/libbar.so.2", ElfBuilder::new().soname("libbar.so.2"));
+    fixture
+}
+
+fn canonical<P: AsRef<Path>>(fixture: &Fixture, path: P) -> String {
+    let path = fixture.path(path).canonicalize().unwrap();
+    path.to_str().unwrap().to_string()
+}
+
+#[test]
+fn gcc_dry_run_resolves_dependencies() {
+    let fixture = gcc_fixture();
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+
+    assert_eq!("sbin -> bin", lines[0]);
+    assert_eq!("usr -> .", lines[1]);
+    for path in &["bin/gcc", "libexec/as", "libexec/cc1"] {
+        let path = fixture.path(path);
+        assert!(lines.contains(&path.to_str().unwrap().to_string()));
+    }
+    // Found through $ORIGIN/../lib in cc1, and then $ORIGIN in libfoo.
+    assert!(lines.contains(&canonical(&fixture, "lib/libfoo.so.1")));
+    assert!(lines.contains(&canonical(&fixture, "lib/libbar.so.2")));
+    assert_eq!(".popsicle/compiler (generated)", lines[lines.len() - 1]);
+}
+
+#[test]
+fn assembler_wrapper_programs_are_packaged() {
+    let fixture = gcc_fixture();
+    fixture.script(
+        "libexec/as",
+        "# Uses llvm-mc instead of llvm-as.\ndir=$(dirname \"$0\")\nexec \"$dir/llvm-mc\" -assemble \"$@\"\n",
+    );
+    fixture.elf("libexec/llvm-mc", ElfBuilder::new().soname("llvm-mc"));
+    fixture.elf("libexec/llvm-as", ElfBuilder::new().soname("llvm-as"));
+
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    let packaged = |name: &str| lines.contains(&canonical(&fixture, name));
+    assert!(packaged("libexec/as"));
+    assert!(packaged("libexec/llvm-mc"));
+    assert!(!packaged("libexec/llvm-as"));
+}
+
+#[test]
+fn clang_dry_run_uses_integrated_assembler() {
+    let fixture = Fixture::new();
+    fixture.clang();
+    fixture.elf("libexec/as", ElfBuilder::new());
+    let lines = fixture.lines(&["--dry-run", "clang"]);
+
+    let assembler = fixture.path("libexec/as");
+    assert!(!lines.contains(&assembler.to_str().unwrap().to_string()));
+    assert!(lines.contains(&"bin/clang++ -> clang".to_string()));
+    assert!(lines.contains(&"proc/cpuinfo (empty)".to_string()));
+}
+
+#[test]
+fn clang_cxx_driver_can_be_packaged() {
+    let fixture = Fixture::new();
+    fixture.clang();
+    let cc1 = r#"    -###) echo ' "'"$0"'" "-cc1" "-emit-obj"' 1>&2 ;;"#;
+    fixture.compiler("clang++", "clang version 6.0.0 (Fake)", cc1);
+    let lines = fixture.lines(&["--dry-run", "clang++"]);
+    assert!(lines.contains(&canonical(&fixture, "bin/clang++")));
+    assert_eq!(".popsicle/compiler (generated)", lines[lines.len() - 1]);
+}
+
+#[test]
+fn compiler_without_version_fails() {
+    let fixture = Fixture::new();
+    fixture.compiler("cc", "Unknown compiler", "");
+    let output = fixture.run(&["--dry-run", "cc"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("no version information"));
+}
+
+#[test]
+fn compiler_identified_by_macros() {
+    let fixture = gcc_fixture();
+    // Translated "-v" output; the macros are only printed in the C locale.
+    let macros = r#"    -dM) [ "$LC_ALL" = C ] && printf '#define __GNUC__ 9\n#define __GNUC_MINOR__ 3\n#define __GNUC_PATCHLEVEL__ 0\n' ;;"#;
+    fixture.compiler("gcc", "gcc-Version 9.3.0 (Fake)", macros);
+    let lines = fixture.lines(&["gcc"]);
+    assert_eq!(1, lines.len());
+    assert!(lines[0].ends_with("/popsicle/gcc/gcc-9.3.0.tar.gz"));
+}
+
+#[test]
+fn ccache_symlinks_are_skipped() {
+    let fixture = gcc_fixture();
+    let ccache = fixture.script("ccache/ccache", "exec \"$@\"\n");
+    std::os::unix::fs::symlink(&ccache, fixture.path("ccache/gcc")).unwrap();
+
+    let path = std::env::var("PATH").unwrap_or_default();
+    let output = fixture
+        .command()
+        .env(
+            "PATH",
+            format!(
+                "{}:{}:{}",
+                fixture.path("ccache").display(),
+                fixture.path("bin").display(),
+                path
+            ),
+        )
+        .args(["--dry-run", "gcc"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert!(lines.contains(&fixture.path("bin/gcc").to_str().unwrap()));
+    assert!(!lines.contains(&ccache.to_str().unwrap()));
+}
+
+#[test]
+fn cached_tarball_is_reused() {
+    let fixture = gcc_fixture();
+    let first = fixture.lines(&["gcc"]);
+    assert_eq!(1, first.len());
+    assert!(first[0].ends_with("/popsicle/gcc/gcc-7.2.0.tar.gz"));
+    let modified = std::fs::metadata(&first[0]).unwrap().modified().unwrap();
+
+    let second = fixture.lines(&["gcc"]);
+    assert_eq!(first, second);
+    let metadata = std::fs::metadata(&second[0]).unwrap();
+    assert_eq!(modified, metadata.modified().unwrap());
+
+    let output = fixture.path("gcc.tar.gz");
+    let lines = fixture.lines(&["--output", output.to_str().unwrap(), "gcc"]);
+    assert_eq!(vec![output.to_str().unwrap().to_string()], lines);
+    assert_eq!(
+        std::fs::read(&first[0]).unwrap(),
+        std::fs::read(&output).unwrap()
+    );
+}
+
+/// Values of the "key = value" lines printed with --sccache.
+fn sccache_config(lines: &[String]) -> std::collections::HashMap<String, String> {
+    assert_eq!("[[dist.toolchains]]", lines[0]);
+    lines[1..]
+        .iter()
+        .map(|line| {
+            let mut parts = line.splitn(2, " = ");
+            let key = parts.next().unwrap().to_string();
+            let value = parts.next().unwrap().trim_matches('"').to_string();
+            (key, value)
+        })
+        .collect()
+}
+
+#[test]
+fn sccache_archives_are_hash_named() {
+    let fixture = gcc_fixture();
+    let config = sccache_config(&fixture.lines(&["--sccache", "gcc"]));
+    assert_eq!("path_override", config["type"]);
+    let compiler = &config["compiler_executable"];
+    assert!(compiler.ends_with("/bin/gcc"));
+    assert_eq!(compiler, &config["archive_compiler_executable"]);
+    let archive = Path::new(&config["archive"]);
+    let name = archive.file_name().unwrap().to_str().unwrap();
+    assert_eq!(128 + ".tar.gz".len(), name.len());
+    assert!(archive.starts_with(fixture.path("cache").canonicalize().unwrap()));
+    fixture.lines(&["verify", "--integrity", archive.to_str().unwrap()]);
+
+    let output = fixture.path("envs");
+    std::fs::create_dir(&output).unwrap();
+    let config = sccache_config(&fixture.lines(&[
+        "--sccache",
+        "--hash-name",
+        "md5",
+        "--no-cache",
+        "--output",
+        output.to_str().unwrap(),
+        "gcc",
+    ]));
+    let archive = Path::new(&config["archive"]);
+    assert_eq!(output.canonicalize().unwrap(), archive.parent().unwrap());
+    assert_eq!(32 + ".tar.gz".len(), archive.file_name().unwrap().len());
+
+    let output = fixture.run(&["--sccache", "--output", "-", "gcc"]);
+    assert!(!output.status.success());
+}
+
+#[test]
+fn package_verify_and_diff() {
+    let fixture = gcc_fixture();
+    let tarball = fixture.path("gcc.tar.gz");
+    let tarball = tarball.to_str().unwrap();
+    fixture.lines(&["--no-cache", "--output", tarball, "gcc"]);
+    fixture.lines(&["verify", "--integrity", tarball]);
+
+    // Nothing changed, rebuilding would produce the same contents.
+    assert!(fixture.lines(&["diff", tarball, "gcc"]).is_empty());
+
+    fixture.elf("lib/libbar.so.2", ElfBuilder::new().soname("libbar.so.3"));
+    let output = fixture.run(&["diff", tarball, "gcc"]);
+    assert!(!output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    assert_eq!("library: libbar.so.2 -> libbar.so.3", lines[0]);
+    assert!(lines[1].starts_with("M ") && lines[1].ends_with("/lib/libbar.so.2"));
+}
+
+#[test]
+fn sysroot_is_packaged_on_request() {
+    let fixture = gcc_fixture();
+    let sysroot = fixture.path("sysroot");
+    std::fs::create_dir_all(sysroot.join("usr/include")).unwrap();
+    std::fs::create_dir_all(sysroot.join("usr/lib")).unwrap();
+    std::fs::write(sysroot.join("usr/include/stdio.h"), b"stdio").unwrap();
+    std::fs::write(sysroot.join("usr/lib/crt1.o"), b"crt1").unwrap();
+    std::fs::write(sysroot.join("usr/lib/libc.a"), b"libc").unwrap();
+    let print_sysroot = format!("    -print-sysroot) echo '{}' ;;", sysroot.display());
+    fixture.compiler("gcc", "gcc version 7.2.0 (Fake)", &print_sysroot);
+
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(!lines.iter().any(|line| line.contains("/sysroot/")));
+
+    let lines = fixture.lines(&["--dry-run", "--sysroot", "compile", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "sysroot/usr/include/stdio.h")));
+    assert!(lines.contains(&canonical(&fixture, "sysroot/usr/lib/crt1.o")));
+    assert!(!lines.contains(&canonical(&fixture, "sysroot/usr/lib/libc.a")));
+
+    let lines = fixture.lines(&["--dry-run", "--sysroot", "full", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "sysroot/usr/lib/libc.a")));
+}
+
+#[test]
+fn missing_sysroot_is_ignored() {
+    let fixture = gcc_fixture();
+    let print_sysroot = "    -print-sysroot) echo /nonexistent/sysroot ;;";
+    fixture.compiler("gcc", "gcc version 7.2.0 (Fake)", print_sysroot);
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "libexec/cc1")));
+}
+
+#[test]
+fn stripped_tarballs_are_cached_separately() {
+    let fixture = gcc_fixture();
+    let plain = fixture.lines(&["gcc"]);
+    let stripped = fixture.lines(&["--strip", "gcc"]);
+    assert!(plain[0].ends_with("/popsicle/gcc/gcc-7.2.0.tar.gz"));
+    assert!(stripped[0].ends_with("/popsicle/gcc/gcc-7.2.0-stripped.tar.gz"));
+    assert!(Path::new(&plain[0]).is_file());
+    fixture.lines(&["verify", "--integrity", &stripped[0]]);
+}
+
+#[test]
+fn relocatable_objects_are_not_stripped() {
+    let fixture = gcc_fixture();
+    let sysroot = fixture.path("sysroot");
+    std::fs::create_dir_all(sysroot.join("usr/lib")).unwrap();
+    let object = common::relocatable_object();
+    std::fs::write(sysroot.join("usr/lib/crt1.o"), &object).unwrap();
+    let print_sysroot = format!("    -print-sysroot) echo '{}' ;;", sysroot.display());
+    fixture.compiler("gcc", "gcc version 7.2.0 (Fake)", &print_sysroot);
+
+    let tarball = fixture.path("gcc.tar.gz");
+    let tarball = tarball.to_str().unwrap();
+    let args = ["--strip", "--sysroot", "compile", "--no-cache"];
+    fixture.lines(&[&args[..], &["--output", tarball, "gcc"]].concat());
+
+    let file = std::fs::File::open(tarball).unwrap();
+    let mut archive = tar::Archive::new(libflate::gzip::Decoder::new(file).unwrap());
+    let mut entry = archive
+        .entries()
+        .unwrap()
+        .map(|entry| entry.unwrap())
+        .find(|entry| entry.path().unwrap().ends_with("sysroot/usr/lib/crt1.o"))
+        .unwrap();
+    let mut packaged = Vec::new();
+    std::io::Read::read_to_end(&mut entry, &mut packaged).unwrap();
+    let elf = goblin::elf::Elf::parse(&packaged).unwrap();
+    let types: Vec<u32> = elf.section_headers.iter().map(|sh| sh.sh_type).collect();
+    assert!(types.contains(&goblin::elf::section_header::SHT_SYMTAB));
+    assert!(types.contains(&goblin::elf::section_header::SHT_RELA));
+    assert_eq!(object, packaged);
+}
+
+#[test]
+fn graph_shows_dependency_chain() {
+    let fixture = gcc_fixture();
+    std::fs::write(fixture.path("libexec/specs"), b"*cc1:\n").unwrap();
+    let lines = fixture.lines(&["graph", "gcc"]);
+    // Files added without scanning them are listed as well.
+    let specs = fixture.path("libexec/specs");
+    assert!(lines.contains(&format!("{} [6 B]", specs.display())));
+    let cc1 = lines
+        .iter()
+        .position(|line| line.starts_with(fixture.path("libexec/cc1").to_str().unwrap()))
+        .unwrap();
+    assert!(lines[cc1].ends_with(" total]"));
+    assert!(lines[cc1 + 1].starts_with("└── ") && lines[cc1 + 1].contains("/lib/libfoo.so.1 ["));
+    assert!(
+        lines[cc1 + 2].starts_with("    └── ") && lines[cc1 + 2].contains("/lib/libbar.so.2 [")
+    );
+
+    let dot = fixture.lines(&["graph", "--format", "dot", "gcc"]);
+    assert_eq!("digraph popsicle {", dot[0]);
+    // The fake compiler driver is a script, which adds more edges for /bin/sh.
+    assert!(dot.iter().filter(|line| line.contains(" -> ")).count() >= 2);
+    assert_eq!(Some(&"}".to_string()), dot.last());
+}
+
+#[test]
+fn library_path_is_searched_on_request() {
+    let fixture = gcc_fixture();
+    fixture.elf(
+        "libexec/cc1",
+        ElfBuilder::new()
+            .needed("libfoo.so.1")
+            .needed("libbaz.so.3")
+            .runpath("$ORIGIN/../lib"),
+    );
+    fixture.elf(
+        "opt/lib/libbaz.so.3",
+        ElfBuilder::new().soname("libbaz.so.3"),
+    );
+    let libbaz = canonical(&fixture, "opt/lib/libbaz.so.3");
+
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(!lines.contains(&libbaz));
+
+    let library_path = fixture.path("opt/lib");
+    let library_path = library_path.to_str().unwrap();
+    let lines = fixture.lines(&["--dry-run", "--library-path", library_path, "gcc"]);
+    assert!(lines.contains(&libbaz));
+    // The loader on the remote side does not get the library path.
+    assert!(lines.contains(&format!("lib/libbaz.so.3 -> {}", libbaz)));
+
+    let output = fixture
+        .command()
+        .env("LD_LIBRARY_PATH", format!(":{}", library_path))
+        .args(["--dry-run", "--ld-library-path", "gcc"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.lines().any(|line| line == libbaz));
+}
+
+#[test]
+fn library_path_takes_precedence_over_runpath() {
+    let fixture = gcc_fixture();
+    fixture.elf(
+        "opt/lib/libfoo.so.1",
+        ElfBuilder::new().soname("libfoo.so.1"),
+    );
+    let library_path = fixture.path("opt/lib");
+    let lines = fixture.lines(&[
+        "--dry-run",
+        "--library-path",
+        library_path.to_str().unwrap(),
+        "gcc",
+    ]);
+    assert!(lines.contains(&canonical(&fixture, "opt/lib/libfoo.so.1")));
+    assert!(!lines.contains(&canonical(&fixture, "lib/libfoo.so.1")));
+    assert!(!lines.contains(&canonical(&fixture, "lib/libbar.so.2")));
+}
+
+/// A fixture where cc1 has the given DT_RPATH and DT_RUNPATH, and needs
+/// "liba.so.1".
+fn run_path_fixture(rpath: Option<&str>, runpath: Option<&str>) -> Fixture {
+    let fixture = gcc_fixture();
+    let mut cc1 = ElfBuilder::new().needed("liba.so.1");
+    if let Some(rpath) = rpath {
+        cc1 = cc1.rpath(rpath);
+    }
+    if let Some(runpath) = runpath {
+        cc1 = cc1.runpath(runpath);
+    }
+    fixture.elf("libexec/cc1", cc1);
+    fixture
+}
+
+#[test]
+fn run_path_variables_are_expanded() {
+    let fixture = run_path_fixture(None, Some("$ORIGIN/../$LIB:$ORIGIN/../${PLATFORM}"));
+    fixture.elf("lib64/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "lib64/liba.so.1")));
+
+    std::fs::remove_file(fixture.path("lib64/liba.so.1")).unwrap();
+    fixture.elf("x86_64/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "x86_64/liba.so.1")));
+}
+
+#[test]
+fn rpath_is_inherited_by_libraries() {
+    let fixture = run_path_fixture(Some("$ORIGIN/../rpath"), None);
+    fixture.elf(
+        "rpath/liba.so.1",
+        ElfBuilder::new().soname("liba.so.1").needed("libb.so.1"),
+    );
+    fixture.elf("rpath/libb.so.1", ElfBuilder::new().soname("libb.so.1"));
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "rpath/liba.so.1")));
+    assert!(lines.contains(&canonical(&fixture, "rpath/libb.so.1")));
+}
+
+#[test]
+fn rpath_is_ignored_with_runpath() {
+    let fixture = run_path_fixture(Some("$ORIGIN/../rpath"), Some("$ORIGIN/../runpath"));
+    fixture.elf("rpath/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    fixture.elf("runpath/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "runpath/liba.so.1")));
+    assert!(!lines.contains(&canonical(&fixture, "rpath/liba.so.1")));
+}
+
+#[test]
+fn inherited_rpath_is_ignored_with_runpath() {
+    let fixture = run_path_fixture(Some("$ORIGIN/../rpath"), None);
+    // The needs of liba.so.1 are not looked up in the DT_RPATH of cc1...
+    fixture.elf(
+        "rpath/liba.so.1",
+        ElfBuilder::new()
+            .needed("libb.so.1")
+            .needed("libc.so.1")
+            .runpath("$ORIGIN/../runpath"),
+    );
+    fixture.elf("rpath/libb.so.1", ElfBuilder::new().soname("libb.so.1"));
+    // ...but it is still passed down to the libraries it loads.
+    fixture.elf(
+        "runpath/libc.so.1",
+        ElfBuilder::new().soname("libc.so.1").needed("libd.so.1"),
+    );
+    fixture.elf("rpath/libd.so.1", ElfBuilder::new().soname("libd.so.1"));
+
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "rpath/liba.so.1")));
+    assert!(!lines.contains(&canonical(&fixture, "rpath/libb.so.1")));
+    assert!(lines.contains(&canonical(&fixture, "runpath/libc.so.1")));
+    assert!(lines.contains(&canonical(&fixture, "rpath/libd.so.1")));
+}
+
+#[test]
+fn rpath_takes_precedence_over_library_path() {
+    let fixture = run_path_fixture(Some("$ORIGIN/../rpath"), None);
+    fixture.elf("rpath/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    fixture.elf("opt/lib/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    let library_path = fixture.path("opt/lib");
+    let lines = fixture.lines(&[
+        "--dry-run",
+        "--library-path",
+        library_path.to_str().unwrap(),
+        "gcc",
+    ]);
+    assert!(lines.contains(&canonical(&fixture, "rpath/liba.so.1")));
+    assert!(!lines.contains(&canonical(&fixture, "opt/lib/liba.so.1")));
+}
+
+#[test]
+fn libraries_for_other_architectures_are_skipped() {
+    let fixture = run_path_fixture(
+        Some("$ORIGIN/../lib32:$ORIGIN/../arm64:$ORIGIN/../lib64"),
+        None,
+    );
+    fixture.elf(
+        "lib32/liba.so.1",
+        ElfBuilder::new().class32().soname("liba.so.1"),
+    );
+    fixture.elf(
+        "arm64/liba.so.1",
+        ElfBuilder::new().machine(EM_AARCH64).soname("liba.so.1"),
+    );
+    fixture.elf("lib64/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(lines.contains(&canonical(&fixture, "lib64/liba.so.1")));
+    assert!(!lines.contains(&canonical(&fixture, "lib32/liba.so.1")));
+    assert!(!lines.contains(&canonical(&fixture, "arm64/liba.so.1")));
+}
+
+#[test]
+fn missing_libraries_policy() {
+    let fixture = run_path_fixture(None, None);
+    let cc1 = fixture.path("libexec/cc1");
+    let summary = format!("liba.so.1 (needed by {})", cc1.display());
+
+    let output = fixture.run(&["--dry-run", "gcc"]);
+    assert!(output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&summary));
+
+    let output = fixture.run(&["--dry-run", "--missing-libs", "ignore", "gcc"]);
+    assert!(output.status.success());
+    assert!(!String::from_utf8_lossy(&output.stderr).contains(&summary));
+
+    let output = fixture.run(&["--missing-libs", "error", "gcc"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains(&summary));
+    assert!(!fixture.path("cache/popsicle/gcc/gcc-7.2.0.tar.gz").exists());
+}
+
+#[test]
+fn hwcaps_variants_are_packaged_with_baseline() {
+    let fixture = run_path_fixture(Some("$ORIGIN/../rpath"), None);
+    fixture.elf("rpath/liba.so.1", ElfBuilder::new().soname("liba.so.1"));
+    fixture.elf(
+        "rpath/glibc-hwcaps/x86-64-v3/liba.so.1",
+        ElfBuilder::new().soname("liba.so.1").runpath("$ORIGIN"),
+    );
+    let baseline = canonical(&fixture, "rpath/liba.so.1");
+    let variant = canonical(&fixture, "rpath/glibc-hwcaps/x86-64-v3/liba.so.1");
+
+    let lines = fixture.lines(&["--dry-run", "--hwcaps", "x86-64-v3", "gcc"]);
+    assert!(lines.contains(&variant));
+    assert!(lines.contains(&baseline));
+
+    for level in &["x86-64-v2", "none"] {
+        let lines = fixture.lines(&["--dry-run", "--hwcaps", level, "gcc"]);
+        assert!(!lines.contains(&variant));
+        assert!(lines.contains(&baseline));
+    }
+}
+
+#[test]
+fn gcc_languages_are_selectable() {
+    let fixture = gcc_fixture();
+    fixture.elf("libexec/cc1plus", ElfBuilder::new().soname("cc1plus"));
+    fixture.elf("libexec/f951", ElfBuilder::new().soname("f951"));
+    fixture.script("bin/g++", "exec gcc -x c++ \"$@\"\n");
+    fixture.script("bin/gfortran", "exec gcc -x f95 \"$@\"\n");
+    let packaged = |lines: &[String], path: &str| {
+        lines.contains(&fixture.path(path).to_str().unwrap().to_string())
+    };
+
+    // C and C++ by default.
+    let lines = fixture.lines(&["--dry-run", "gcc"]);
+    assert!(packaged(&lines, "libexec/cc1plus") && packaged(&lines, "bin/g++"));
+    assert!(!packaged(&lines, "libexec/f951") && !packaged(&lines, "bin/gfortran"));
+
+    let lines = fixture.lines(&["--dry-run", "--languages", "c,fortran", "gcc"]);
+    assert!(packaged(&lines, "libexec/cc1"));
+    assert!(!packaged(&lines, "libexec/cc1plus") && !packaged(&lines, "bin/g++"));
+    assert!(packaged(&lines, "libexec/f951") && packaged(&lines, "bin/gfortran"));
+
+    let lines = fixture.lines(&["--dry-run", "--languages", "all", "gcc"]);
+    assert!(packaged(&lines, "libexec/cc1plus") && packaged(&lines, "libexec/f951"));
+
+    let output = fixture.run(&["--dry-run", "--languages", "go", "gcc"]);
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("cannot find go1"));
+}
+
+#[test]
+fn gcc_specs_and_plugins_are_packaged() {
+    let fixture = gcc_fixture();
+    std::fs::write(fixture.path("libexec/specs"), "*cc1_options:\n+ -fPIE\n").unwrap();
+    std::fs::write(fixture.path("hardened.specs"), "*link:\n+ -z now\n").unwrap();
+    fixture.elf(
+        "libexec/plugin/annobin.so",
+        ElfBuilder::new().soname("annobin.so").needed("libbar.so.2"),
+    );
+    fixture.elf(
+        "libexec/plugin/libcc1plugin.so",
+        ElfBuilder::new().soname("libcc1plugin.so.0"),
+    );
+    std::fs::create_dir_all(fixture.path("libexec/plugin/include")).unwrap();
+    std::fs::write(fixture.path("libexec/plugin/include/tree.h"), "").unwrap();
+
+    let output = fixture
+        .command()
+        .env("GCC_SPECS", fixture.path("hardened.specs"))
+        .args(["--dry-run", "gcc"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = stdout.lines().collect();
+    for path in &[
+        "libexec/specs",
+        "hardened.specs",
+        "libexec/plugin/annobin.so",
+    ] {
+        assert!(lines.contains(&fixture.path(path).to_str().unwrap()));
+    }
+    assert!(!stdout.contains("tree.h"));
+    assert!(!stdout.contains("libcc1plugin.so"));
+}
+
+#[test]
+fn clang_config_files_are_packaged() {
+    let fixture = Fixture::new();
+    fixture.clang();
+    std::fs::write(fixture.path("bin/clang.cfg"), "@common.cfg\n").unwrap();
+    std::fs::write(
+        fixture.path("bin/clang++.cfg"),
+        "-stdlib=libc++ @common.cfg\n",
+    )
+    .unwrap();
+    std::fs::write(fixture.path("bin/common.cfg"), "-fstack-protector-strong\n").unwrap();
+    std::fs::write(fixture.path("bin/unrelated.cfg"), "").unwrap();
+
+    let lines = fixture.lines(&["--dry-run", "clang"]);
+    for path in &["bin/clang.cfg", "bin/clang++.cfg", "bin/common.cfg"] {
+        assert!(lines.contains(&fixture.path(path).to_str().unwrap().to_string()));
+    }
+    assert!(!lines.iter().any(|line| line.ends_with("unrelated.cfg")));
+}
+
+#[test]
+fn rustc_toolchain_is_packaged() {
+    let fixture = Fixture::new();
+    fixture.gcc();
+    let sysroot = fixture.path("rust");
+    fixture.script(
+        "bin/rustc",
+        &format!(
+            r#"case "$1" in
+    --print) echo "{}" ;;
+    -vV) printf 'rustc 1.75.0 (82e1608df 2023-12-21)\nhost: x86_64-unknown-linux-gnu\n' ;;
+    *) echo "$*" >> "{}" ; exit 1 ;;
+esac
+"#,
+            sysroot.display(),
+            fixture.path("rustc.log").display()
+        ),
+    );
+    fixture.elf(
+        "rust/bin/rustc",
+        ElfBuilder::new()
+            .needed("librustc_driver-0123.so")
+            .runpath("$ORIGIN/../lib"),
+    );
+    fixture.elf(
+        "rust/lib/librustc_driver-0123.so",
+        ElfBuilder::new().soname("librustc_driver-0123.so"),
+    );
+    let host = "rust/lib/rustlib/x86_64-unknown-linux-gnu";
+    fixture.elf(
+        format!("{}/bin/rust-lld", host),
+        ElfBuilder::new().soname("rust-lld"),
+    );
+    let rlib = fixture.path(format!("{}/lib/libstd-0123.rlib", host));
+    std::fs::create_dir_all(rlib.parent().unwrap()).unwrap();
+    std::fs::write(&rlib, "!<arch>\n").unwrap();
+
+    // What the linker needs to link programs, found by the fake gcc.
+    fixture.elf(
+        "libexec/liblto_plugin.so",
+        ElfBuilder::new().soname("liblto_plugin.so"),
+    );
+    std::fs::write(fixture.path("libexec/crtbegin.o"), "crtbegin").unwrap();
+    std::fs::write(fixture.path("libexec/libgcc.a"), "!<arch>\nlibgcc").unwrap();
+    let nonshared = fixture.path("lib/libc_nonshared.a");
+    std::fs::create_dir_all(nonshared.parent().unwrap()).unwrap();
+    std::fs::write(&nonshared, "!<arch>\n\n").unwrap();
+    std::fs::write(
+        fixture.path("libexec/libc.so"),
+        format!("/* GNU ld script */\nGROUP ( {} )\n", nonshared.display()),
+    )
+    .unwrap();
+
+    let lines = fixture.lines(&["--dry-run", "--rust-linker", "gcc", "rustc"]);
+    let packaged = |path: &Path| lines.contains(&path.to_str().unwrap().to_string());
+    assert!(packaged(&sysroot.join("bin/rustc")));
+    assert!(!packaged(&fixture.path("bin/rustc")));
+    assert!(lines.contains(&canonical(&fixture, "rust/lib/librustc_driver-0123.so")));
+    assert!(packaged(&fixture.path(format!("{}/bin/rust-lld", host))));
+    assert!(packaged(&rlib));
+    assert!(packaged(&fixture.path("bin/gcc")));
+    for file in &["liblto_plugin.so", "crtbegin.o", "libgcc.a", "libc.so"] {
+        assert!(packaged(&fixture.path("libexec").join(file)), "{}", file);
+    }
+    assert!(packaged(&nonshared));
+    assert_eq!(".popsicle/compiler (generated)", lines[lines.len() - 1]);
+    // Only "-vV" and "--print sysroot" are run.
+    assert!(!fixture.path("rustc.log").exists());
+
+    // sccache runs the proxy, which is not in the archive.
+    std::fs::create_dir(fixture.path("envs")).unwrap();
+    let config = sccache_config(&fixture.lines(&[
+        "--sccache",
+        "--no-cache",
+        "--output",
+        fixture.path("envs").to_str().unwrap(),
+        "--rust-linker",
+        "gcc",
+        "rustc",
+    ]));
+    assert_eq!(
+        fixture.path("bin/rustc").to_str().unwrap(),
+        config["compiler_executable"]
+    );
+    assert_eq!(
+        sysroot.join("bin/rustc").to_str().unwrap(),
+        config["archive_compiler_executable"]
+    );
+
+    let output = fixture.run(&[
+        "--dry-run",
+        "--rust-linker",
+        "gcc",
+        "--rust-target",
+        "aarch64-unknown-linux-gnu",
+        "rustc",
+    ]);
+    assert!(!output.status.success());
+}